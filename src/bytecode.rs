@@ -1,14 +1,29 @@
-use std::hash::{Hash, Hasher};
+use std::{
+	fmt::{Display, Formatter},
+	hash::{Hash, Hasher},
+};
 
 use byteorder::LittleEndian;
 
+mod diff;
+mod instruction;
+mod minimize;
+mod optimize;
 mod reader;
+mod stats;
 mod writer;
 
+pub use diff::*;
+pub use instruction::*;
+pub use minimize::*;
+pub use optimize::*;
 pub use reader::*;
+pub use stats::*;
 pub use writer::*;
 
-/// The endianness of bytecode. Used in [`BytecodeReader`] and [`BytecodeWriter`].
+/// The default endianness of bytecode. Used in [`BytecodeReader`], [`BytecodeWriter`] and the VM unless another
+/// [`ByteOrder`](byteorder::ByteOrder) is specified, e.g. to run bytecode produced by a big-endian emitter (see
+/// [`VirtualMachine::interpret_with_endianness`](crate::vm::VirtualMachine::interpret_with_endianness)).
 pub type Endianness = LittleEndian;
 /// The type of constant index in a [`Bytecode`]. Defined using typedef to deal with possible changes in the future.
 pub type ConstantIndex = u16;
@@ -27,6 +42,7 @@ pub type CallPosition = u16;
 /// sequence of instruction and is good for performance. Tree structures at the source code level (e.g. control
 /// flows) are implemented by several kinds of jump instructions.
 #[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum OperationCode {
 	/// Load a constant into the VM stack, with its index stored as [`ConstantIndex`] following the operation code.
 	Constant,
@@ -99,6 +115,9 @@ pub enum OperationCode {
 	Return,
 
 	Print,
+	/// Pops the top element of the stack and prints it to stderr instead of stdout, e.g. for diagnostics which
+	/// shouldn't mix with the program output.
+	PrintErr,
 
 	/// Guard variant to detect invalid operation codes.
 	Impossible,
@@ -139,11 +158,21 @@ impl PartialEq for Constant {
 
 impl Eq for Constant {}
 
+impl Display for Constant {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Constant::Number(n) => write!(f, "<{}>", n),
+			Constant::String(s) => write!(f, "<{:?}>", s),
+		}
+	}
+}
+
 /// The bytecode.
 ///
 /// Bytecode is the binary representation of a program. As Niklaus Wirth describes, the bytecode is also the
 /// combination of data structure and algorithms. More specifically, a [`Bytecode`] of Mussel VM consists of a
 /// [`OperationCode`] sequence and some [`Constant`]s.
+#[derive(Debug, Clone)]
 pub struct Bytecode {
 	pub code: Vec<u8>,
 	pub constants: Vec<Constant>,
@@ -0,0 +1,175 @@
+use std::{
+	collections::HashMap,
+	fmt::{Display, Formatter},
+	ops::Range,
+};
+
+use crate::bytecode::{Bytecode, Constant, ConstantIndex, Instruction};
+
+/// A structured difference between two [`Bytecode`]s, produced by [`diff`].
+///
+/// Displaying it gives a human-readable report, in the spirit of a unified diff.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BytecodeDiff {
+	/// Constants which only exist in the new bytecode, along with their indices in it.
+	pub constants_added: Vec<(ConstantIndex, Constant)>,
+	/// Constants which only exist in the old bytecode, along with their indices in it.
+	pub constants_removed: Vec<(ConstantIndex, Constant)>,
+	/// The changed instruction ranges, in code order.
+	pub hunks: Vec<Hunk>,
+}
+
+/// A range of instructions replaced by another one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hunk {
+	/// The byte range of the removed instructions in the old code. It's empty for pure insertions.
+	pub old: Range<usize>,
+	/// The byte range of the added instructions in the new code. It's empty for pure removals.
+	pub new: Range<usize>,
+	/// The instructions removed from the old code.
+	pub removed: Vec<Instruction>,
+	/// The instructions added to the new code.
+	pub added: Vec<Instruction>,
+}
+
+impl BytecodeDiff {
+	/// Returns whether the two bytecode are identical, up to the order of constants.
+	pub fn is_empty(&self) -> bool {
+		self.constants_added.is_empty()
+			&& self.constants_removed.is_empty()
+			&& self.hunks.is_empty()
+	}
+}
+
+/// Compares two [`Bytecode`]s, e.g. the output of different compiler versions or optimization levels.
+///
+/// Constants are compared as a (multi)set, since their order only matters through the indices in the code, which
+/// show up in the instruction diff anyway. Instructions are compared by their longest common subsequence, so a
+/// local change shows up as a local hunk, even if it shifts the positions of everything after it.
+pub fn diff(old: &Bytecode, new: &Bytecode) -> BytecodeDiff {
+	BytecodeDiff {
+		constants_added: exclusive_constants(new, old),
+		constants_removed: exclusive_constants(old, new),
+		hunks: hunks(old, new),
+	}
+}
+
+/// Returns constants of `this` which `that` doesn't have.
+fn exclusive_constants(this: &Bytecode, that: &Bytecode) -> Vec<(ConstantIndex, Constant)> {
+	let mut available: HashMap<&Constant, usize> = HashMap::new();
+	for constant in &that.constants {
+		*available.entry(constant).or_default() += 1;
+	}
+	let mut exclusive = Vec::new();
+	for (index, constant) in this.constants.iter().enumerate() {
+		match available.get_mut(constant) {
+			Some(count) if *count > 0 => *count -= 1,
+			_ => exclusive.push((index as ConstantIndex, constant.clone())),
+		}
+	}
+	exclusive
+}
+
+/// Returns the position of the `index`-th decoded instruction, or `end` if it's past the last one.
+fn position(instructions: &[(usize, Instruction)], index: usize, end: usize) -> usize {
+	match instructions.get(index) {
+		Some((position, _)) => *position,
+		None => end,
+	}
+}
+
+fn hunks(old: &Bytecode, new: &Bytecode) -> Vec<Hunk> {
+	let a: Vec<_> = old.instructions().collect();
+	let b: Vec<_> = new.instructions().collect();
+	// Common prefix and suffix are trimmed first, which is the usual case of a small change in a large program and
+	// keeps the quadratic table small.
+	let prefix = a.iter().zip(&b).take_while(|(x, y)| x.1 == y.1).count();
+	let suffix = a[prefix..]
+		.iter()
+		.rev()
+		.zip(b[prefix..].iter().rev())
+		.take_while(|(x, y)| x.1 == y.1)
+		.count();
+	let (n, m) = (a.len() - prefix - suffix, b.len() - prefix - suffix);
+
+	// lcs[i][j] is the length of the longest common subsequence of a[prefix + i..] and b[prefix + j..] (within the
+	// trimmed middle part).
+	let mut lcs = vec![vec![0u32; m + 1]; n + 1];
+	for i in (0..n).rev() {
+		for j in (0..m).rev() {
+			lcs[i][j] = if a[prefix + i].1 == b[prefix + j].1 {
+				lcs[i + 1][j + 1] + 1
+			} else {
+				lcs[i + 1][j].max(lcs[i][j + 1])
+			};
+		}
+	}
+
+	let mut hunks = Vec::new();
+	let (mut i, mut j) = (0, 0);
+	while i < n || j < m {
+		if i < n && j < m && a[prefix + i].1 == b[prefix + j].1 {
+			i += 1;
+			j += 1;
+			continue;
+		}
+		let (start_i, start_j) = (i, j);
+		while (i < n || j < m) && !(i < n && j < m && a[prefix + i].1 == b[prefix + j].1) {
+			if j >= m || (i < n && lcs[i + 1][j] >= lcs[i][j + 1]) {
+				i += 1;
+			} else {
+				j += 1;
+			}
+		}
+		hunks.push(Hunk {
+			old: position(&a, prefix + start_i, old.code.len())
+				..position(&a, prefix + i, old.code.len()),
+			new: position(&b, prefix + start_j, new.code.len())
+				..position(&b, prefix + j, new.code.len()),
+			removed: a[prefix + start_i..prefix + i]
+				.iter()
+				.map(|(_, x)| *x)
+				.collect(),
+			added: b[prefix + start_j..prefix + j]
+				.iter()
+				.map(|(_, x)| *x)
+				.collect(),
+		});
+	}
+	hunks
+}
+
+impl Display for BytecodeDiff {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		if !self.constants_added.is_empty() || !self.constants_removed.is_empty() {
+			writeln!(f, "const:")?;
+			for (index, constant) in &self.constants_removed {
+				writeln!(f, "-\t{:02} {}", index, constant)?;
+			}
+			for (index, constant) in &self.constants_added {
+				writeln!(f, "+\t{:02} {}", index, constant)?;
+			}
+		}
+		if !self.hunks.is_empty() {
+			writeln!(f, "code:")?;
+		}
+		for hunk in &self.hunks {
+			writeln!(
+				f,
+				"@@ {:#06X}..{:#06X} => {:#06X}..{:#06X} @@",
+				hunk.old.start, hunk.old.end, hunk.new.start, hunk.new.end
+			)?;
+			let mut position = hunk.old.start;
+			for instruction in &hunk.removed {
+				writeln!(f, "-\t{:02} {}", position, instruction)?;
+				position += instruction.size();
+			}
+			let mut position = hunk.new.start;
+			for instruction in &hunk.added {
+				writeln!(f, "+\t{:02} {}", position, instruction)?;
+				position += instruction.size();
+			}
+		}
+		Ok(())
+	}
+}
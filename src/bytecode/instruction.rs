@@ -0,0 +1,293 @@
+use std::{
+	fmt::{Display, Formatter},
+	iter, mem,
+};
+
+use byteorder::ByteOrder;
+
+use crate::bytecode::{
+	Bytecode, BytecodeReader, BytecodeWriter, CallPosition, ConstantIndex, Emit, Endianness, Fetch,
+	GlobalIndex, JumpOffset, LocalOffset, OperationCode,
+};
+
+/// Generates the [`Instruction`] enum and its conversions from a table of operation codes and their operands.
+///
+/// Each operation code is listed with the types of the operands following it, which is the single source of truth
+/// of the instruction layout: decoding, encoding and the size of instructions are all derived from it.
+macro_rules! register_instructions {
+	($($variant: ident $(($($operand: ident: $t: ty), +))?); * $(;)?) => {
+		/// A decoded instruction, i.e. an [`OperationCode`] together with its operands.
+		///
+		/// The VM executes operation codes directly from the byte sequence. Instructions are for tools which
+		/// analyze or transform bytecode, where dealing with raw bytes is error-prone.
+		#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+		pub enum Instruction {
+			$($variant $(($($t), +))?), *
+		}
+
+		impl Instruction {
+			/// Returns the operation code of the instruction.
+			pub fn opcode(&self) -> OperationCode {
+				match self {
+					$(Instruction::$variant { .. } => OperationCode::$variant), *
+				}
+			}
+
+			/// Returns the size of the instruction in bytes, including the operation code itself.
+			pub fn size(&self) -> usize {
+				self.opcode().size()
+			}
+		}
+
+		impl OperationCode {
+			/// Returns the size in bytes of an instruction with this operation code, including its operands.
+			pub fn size(&self) -> usize {
+				match self {
+					$(OperationCode::$variant => 1 $($(+ mem::size_of::<$t>())+)?,)*
+					OperationCode::Impossible => 1,
+				}
+			}
+		}
+
+		impl<E: ByteOrder> Fetch<Instruction> for BytecodeReader<'_, E> {
+			fn fetch(&mut self) -> Instruction {
+				let opcode: OperationCode = self.fetch();
+				match opcode {
+					$(OperationCode::$variant => {
+						$($(let $operand: $t = self.fetch();)+)?
+						Instruction::$variant $(($($operand), +))?
+					})*
+					OperationCode::Impossible => unreachable!(),
+				}
+			}
+		}
+
+		impl<E: ByteOrder> Emit<Instruction> for BytecodeWriter<'_, E> {
+			fn emit(&mut self, instruction: Instruction) {
+				self.emit(instruction.opcode());
+				match instruction {
+					$(Instruction::$variant $(($($operand), +))? => {
+						$($(self.emit($operand);)+)?
+					})*
+				}
+			}
+		}
+
+		impl Display for Instruction {
+			fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+				match self {
+					$(Instruction::$variant $(($($operand), +))? => {
+						let operands: &[String] = &[$($($operand.to_string()), +)?];
+						if operands.is_empty() {
+							write!(f, "{}", self.opcode())
+						} else {
+							write!(f, "{:<11} {}", self.opcode(), operands.join(" "))
+						}
+					})*
+				}
+			}
+		}
+	};
+}
+
+register_instructions! {
+	Constant(index: ConstantIndex);
+	Nil;
+	True;
+	False;
+	Fun(position: CallPosition, arity: LocalOffset);
+	Negate;
+	Not;
+	Add;
+	Subtract;
+	Multiply;
+	Divide;
+	Equal;
+	Greater;
+	Less;
+	GetGlobal(index: GlobalIndex);
+	SetGlobal(index: GlobalIndex);
+	GetLocal(offset: LocalOffset);
+	SetLocal(offset: LocalOffset);
+	Pop;
+	Closure(position: CallPosition, arity: LocalOffset);
+	Capture(offset: LocalOffset);
+	GetUpvalue(offset: LocalOffset);
+	SetUpvalue(offset: LocalOffset);
+	JumpIfFalse(offset: JumpOffset);
+	Jump(offset: JumpOffset);
+	Call(position: CallPosition, frame_offset: LocalOffset);
+	Invoke;
+	Return;
+	Print;
+	PrintErr;
+}
+
+impl Instruction {
+	/// Returns the absolute target of a jump instruction at `position`, or [`None`] for other instructions.
+	///
+	/// Jump offsets are relative to the end of the jump instruction, since the VM has fetched the operand when it
+	/// jumps.
+	pub fn jump_target(&self, position: usize) -> Option<usize> {
+		match self {
+			Instruction::Jump(offset) | Instruction::JumpIfFalse(offset) => {
+				Some((position + self.size()).wrapping_add_signed(*offset as isize))
+			}
+			_ => None,
+		}
+	}
+
+	/// Returns the function entry referred by the instruction if any, along with the number of arguments its call
+	/// frame starts with.
+	pub fn function_entry(&self) -> Option<(usize, LocalOffset)> {
+		match self {
+			Instruction::Fun(position, arguments)
+			| Instruction::Closure(position, arguments)
+			| Instruction::Call(position, arguments) => Some((*position as usize, *arguments)),
+			_ => None,
+		}
+	}
+
+	/// Returns the net change of the stack depth after executing the instruction.
+	///
+	/// Calls are considered returned, i.e. their frames are replaced by the return values. Since the arity of the
+	/// callee of [`Instruction::Invoke`] is unknown statically, it's assumed to take no arguments.
+	pub fn stack_effect(&self) -> isize {
+		match self {
+			Instruction::Constant(_)
+			| Instruction::Nil
+			| Instruction::True
+			| Instruction::False
+			| Instruction::Fun(..)
+			| Instruction::GetGlobal(_)
+			| Instruction::GetLocal(_)
+			| Instruction::Closure(..)
+			| Instruction::GetUpvalue(_) => 1,
+			Instruction::Add
+			| Instruction::Subtract
+			| Instruction::Multiply
+			| Instruction::Divide
+			| Instruction::Equal
+			| Instruction::Greater
+			| Instruction::Less
+			| Instruction::Pop
+			| Instruction::Print
+			| Instruction::PrintErr => -1,
+			Instruction::Call(_, frame_offset) => 1 - *frame_offset as isize,
+			Instruction::Negate
+			| Instruction::Not
+			| Instruction::SetGlobal(_)
+			| Instruction::SetLocal(_)
+			| Instruction::Capture(_)
+			| Instruction::SetUpvalue(_)
+			| Instruction::JumpIfFalse(_)
+			| Instruction::Jump(_)
+			| Instruction::Invoke
+			| Instruction::Return => 0,
+		}
+	}
+
+	/// Returns whether the execution never falls through to the next instruction.
+	pub fn is_terminator(&self) -> bool {
+		matches!(self, Instruction::Jump(_) | Instruction::Return)
+	}
+}
+
+impl Display for OperationCode {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		let mnemonic = format!("{:?}", self).to_uppercase();
+		f.pad(&mnemonic)
+	}
+}
+
+/// An iterator decoding the instructions of a [`Bytecode`] one by one, along with their positions.
+///
+/// See [`Bytecode::instructions`].
+pub struct Instructions<'a, E: ByteOrder = Endianness> {
+	reader: BytecodeReader<'a, E>,
+	end: usize,
+}
+
+impl<E: ByteOrder> Iterator for Instructions<'_, E> {
+	type Item = (usize, Instruction);
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if self.reader.position() >= self.end {
+			return None;
+		}
+		let position = self.reader.position();
+		Some((position, self.reader.fetch()))
+	}
+}
+
+impl Bytecode {
+	/// Decodes the instruction at `position`, or returns [`None`] if there's no valid instruction there.
+	///
+	/// This never panics, which makes it suitable for analyzing untrusted bytecode.
+	pub fn instruction_at(&self, position: usize) -> Option<Instruction> {
+		self.instruction_at_with_endianness::<Endianness>(position)
+	}
+
+	/// Decodes the instruction at `position` like [`Bytecode::instruction_at`], reading operands in the byte order
+	/// [`E`].
+	pub fn instruction_at_with_endianness<E: ByteOrder>(
+		&self,
+		position: usize,
+	) -> Option<Instruction> {
+		let mut reader = BytecodeReader::<E>::with_endianness(self);
+		reader.seek(position);
+		reader.fetch_operation()?;
+		reader.seek(position);
+		Some(reader.fetch())
+	}
+
+	/// Decodes the code linearly from the beginning, yielding every instruction and its position.
+	///
+	/// Panics on invalid operation codes or truncated operands, see [`Bytecode::instruction_at`] for the fallible
+	/// counterpart.
+	pub fn instructions(&self) -> Instructions<'_> {
+		self.instructions_with_endianness()
+	}
+
+	/// Decodes the code like [`Bytecode::instructions`], reading operands in the byte order [`E`].
+	pub fn instructions_with_endianness<E: ByteOrder>(&self) -> Instructions<'_, E> {
+		Instructions {
+			reader: BytecodeReader::with_endianness(self),
+			end: self.code.len(),
+		}
+	}
+
+	/// Decodes the code linearly like [`Bytecode::instructions`], but stops at the first position where there's no
+	/// valid instruction instead of panicking.
+	pub(crate) fn valid_instructions(&self) -> impl Iterator<Item = (usize, Instruction)> + '_ {
+		let mut position = 0;
+		iter::from_fn(move || {
+			let instruction = self.instruction_at(position)?;
+			position += instruction.size();
+			Some((position - instruction.size(), instruction))
+		})
+	}
+
+	/// Returns the loop headers, i.e. the targets of back-edges (jumps to the same or an earlier position), in code
+	/// order.
+	///
+	/// Code is laid out in source order, so every loop ends with a backward jump to its header. Checks which only
+	/// need to happen once per iteration, like the VM polling for interrupts, belong to back-edges.
+	///
+	/// The headers are computed on each call by decoding the whole code, and aren't stored along with the chunk. The VM
+	/// doesn't need them, since it recognizes back-edges by the sign of the jump offset as it executes them. Decoding
+	/// stops at the first position where there's no valid instruction, so loops past it aren't found.
+	pub fn loop_headers(&self) -> Vec<usize> {
+		let mut headers: Vec<_> = self
+			.valid_instructions()
+			.filter_map(|(position, instruction)| {
+				instruction
+					.jump_target(position)
+					.filter(|&target| target <= position)
+			})
+			.collect();
+		headers.sort_unstable();
+		headers.dedup();
+		headers
+	}
+}
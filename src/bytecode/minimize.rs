@@ -0,0 +1,93 @@
+use crate::bytecode::{relocate, Bytecode, ConstantIndex, Instruction};
+
+/// Shrinks a [`Bytecode`] while `predicate` still holds, e.g. while it still makes the VM crash.
+///
+/// Instructions are removed in runs, from half of the code down to single instructions, keeping every removal after
+/// which the predicate holds, until nothing more can be removed. Jumps and calls are fixed up the same way as
+/// [`eliminate_dead_code`](crate::bytecode::eliminate_dead_code) does, and a removal after which a jump or function
+/// position no longer refers to an instruction boundary is never kept. Unused constants are dropped at last.
+///
+/// The bytecode must decode into whole instructions, and `predicate` must hold for it in the first place, e.g. it
+/// checks for a certain [`RuntimeError`](crate::vm::RuntimeError).
+pub fn minimize(bytecode: &Bytecode, mut predicate: impl FnMut(&Bytecode) -> bool) -> Bytecode {
+	let mut current = bytecode.clone();
+	let mut run = current.instructions().count().div_ceil(2).max(1);
+	loop {
+		let instructions: Vec<_> = current
+			.instructions()
+			.map(|(position, instruction)| (position, Some(instruction)))
+			.collect();
+		let mut shrunk = false;
+		let mut start = 0;
+		while start < instructions.len() {
+			let end = (start + run).min(instructions.len());
+			let mut candidate = instructions.clone();
+			for (_, instruction) in &mut candidate[start..end] {
+				*instruction = None;
+			}
+			// Removing code around a jump into the middle of an instruction leaves no sensible target, so such a
+			// candidate is treated as if the predicate didn't hold.
+			if let Some(candidate) = relocate(&current, &candidate) {
+				if predicate(&candidate) {
+					current = candidate;
+					shrunk = true;
+					break;
+				}
+			}
+			start = end;
+		}
+		if !shrunk {
+			if run == 1 {
+				break;
+			}
+			run /= 2;
+		}
+	}
+
+	let candidate = drop_unused_constants(&current);
+	if predicate(&candidate) {
+		candidate
+	} else {
+		current
+	}
+}
+
+/// Removes constants which no instruction loads, renumbering the rest. An index past the constants stays past them,
+/// so that a program failing with [`InvalidConstant`](crate::vm::RuntimeErrorKind::InvalidConstant) still does.
+fn drop_unused_constants(bytecode: &Bytecode) -> Bytecode {
+	let mut used = vec![false; bytecode.constants.len()];
+	for (_, instruction) in bytecode.instructions() {
+		if let Instruction::Constant(index) = instruction {
+			if let Some(used) = used.get_mut(index as usize) {
+				*used = true;
+			}
+		}
+	}
+	let mut indices = Vec::with_capacity(used.len());
+	let mut constants = Vec::new();
+	for (constant, used) in bytecode.constants.iter().zip(&used) {
+		indices.push(constants.len() as ConstantIndex);
+		if *used {
+			constants.push(constant.clone());
+		}
+	}
+
+	let instructions: Vec<_> = bytecode
+		.instructions()
+		.map(|(position, instruction)| match instruction {
+			Instruction::Constant(index) => {
+				let index = match indices.get(index as usize) {
+					Some(index) => *index,
+					None => index - (used.len() - constants.len()) as ConstantIndex,
+				};
+				(position, Some(Instruction::Constant(index)))
+			}
+			_ => (position, Some(instruction)),
+		})
+		.collect();
+	let renumbered = Bytecode {
+		code: bytecode.code.clone(),
+		constants,
+	};
+	relocate(&renumbered, &instructions).unwrap_or_else(|| bytecode.clone())
+}
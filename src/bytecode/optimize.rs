@@ -0,0 +1,223 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::bytecode::{Bytecode, BytecodeWriter, CallPosition, Emit, Instruction, JumpOffset};
+
+/// Removes code which can never be executed.
+///
+/// Execution starts at position 0, and functions are entered through the positions referred by `Fun`, `Closure`
+/// and `Call` instructions. Everything reachable from those entries (by falling through or jumping) is kept, and
+/// the rest is removed. A function only referred by dead code is dead as well.
+///
+/// The kept code is compacted, and jump offsets and function positions are fixed up accordingly. If a kept jump or
+/// function position doesn't refer to an instruction boundary, there's no telling what it means after compaction, so
+/// the code is returned unchanged. So is code whose reachable part doesn't decode, while unreachable bytes are removed
+/// whatever they are.
+pub fn eliminate_dead_code(bytecode: &Bytecode) -> Bytecode {
+	let mut reachable = HashSet::new();
+	let mut worklist = vec![0];
+	while let Some(position) = worklist.pop() {
+		if !reachable.insert(position) {
+			continue;
+		}
+		let Some(instruction) = bytecode.instruction_at(position) else {
+			continue;
+		};
+		if let Some(target) = instruction.jump_target(position) {
+			worklist.push(target);
+		}
+		if let Some((entry, _)) = instruction.function_entry() {
+			worklist.push(entry);
+		}
+		if !instruction.is_terminator() {
+			worklist.push(position + instruction.size());
+		}
+	}
+
+	// Only the reachable code is decoded, since the rest may be garbage, and the code falls through into garbage if a
+	// reachable instruction doesn't decode. A jump may also land in the middle of an instruction, which is caught by
+	// the linear decoding as far as it goes, and by reachable instructions overlapping past that.
+	let mut boundaries = HashSet::new();
+	let mut decoded = 0;
+	for (position, instruction) in bytecode.valid_instructions() {
+		boundaries.insert(position);
+		decoded = position + instruction.size();
+	}
+	let mut positions: Vec<_> = reachable
+		.into_iter()
+		.filter(|&position| position != bytecode.code.len())
+		.collect();
+	positions.sort_unstable();
+	let mut instructions = Vec::with_capacity(positions.len());
+	let mut end = 0;
+	for position in positions {
+		let Some(instruction) = bytecode.instruction_at(position) else {
+			return bytecode.clone();
+		};
+		if position < end || (position < decoded && !boundaries.contains(&position)) {
+			return bytecode.clone();
+		}
+		end = position + instruction.size();
+		instructions.push((position, Some(instruction)));
+	}
+	relocate(bytecode, &instructions).unwrap_or_else(|| bytecode.clone())
+}
+
+/// Runs every optimization pass until the code stops shrinking.
+pub fn optimize(bytecode: &Bytecode) -> Bytecode {
+	let mut optimized = eliminate_dead_code(&thread_jumps(bytecode));
+	loop {
+		let next = eliminate_dead_code(&thread_jumps(&optimized));
+		if next.code.len() == optimized.code.len() {
+			return next;
+		}
+		optimized = next;
+	}
+}
+
+/// Simplifies jumps and branches.
+///
+/// - A `JumpIfFalse` right after a `True` is never taken, so it's removed, and one right after a `False` is always
+///   taken, so it becomes a `Jump`. `JumpIfFalse` doesn't pop the condition, so the constant itself stays. Branches
+///   which are also jump targets are left alone, since the condition may come from elsewhere.
+/// - A jump to a `Jump` is redirected to the final target of the chain.
+/// - A jump to the immediately following instruction does nothing, so it's removed.
+///
+/// Code made unreachable by this pass is left in place, see [`eliminate_dead_code`]. A jump whose threaded offset
+/// doesn't fit in a [`JumpOffset`] is left alone. As with [`eliminate_dead_code`], the code is returned unchanged if
+/// a jump or function position doesn't refer to an instruction boundary, or if the code doesn't decode as a whole.
+pub fn thread_jumps(bytecode: &Bytecode) -> Bytecode {
+	let mut instructions: Vec<_> = bytecode
+		.valid_instructions()
+		.map(|(position, instruction)| (position, Some(instruction)))
+		.collect();
+	let end = instructions.last().map_or(0, |(position, instruction)| {
+		position + instruction.unwrap().size()
+	});
+	if end != bytecode.code.len() {
+		return bytecode.clone();
+	}
+	let indices: HashMap<_, _> = instructions
+		.iter()
+		.enumerate()
+		.map(|(index, (position, _))| (*position, index))
+		.collect();
+	let mut targets = HashSet::new();
+	for (position, instruction) in &instructions {
+		let instruction = instruction.unwrap();
+		targets.extend(instruction.jump_target(*position));
+		targets.extend(instruction.function_entry().map(|(entry, _)| entry));
+	}
+
+	for index in 1..instructions.len() {
+		let (position, instruction) = instructions[index];
+		let Some(Instruction::JumpIfFalse(offset)) = instruction else {
+			continue;
+		};
+		if targets.contains(&position) {
+			continue;
+		}
+		match instructions[index - 1].1 {
+			Some(Instruction::True) => instructions[index].1 = None,
+			Some(Instruction::False) => instructions[index].1 = Some(Instruction::Jump(offset)),
+			_ => {}
+		}
+	}
+
+	for index in 0..instructions.len() {
+		let (position, Some(instruction)) = instructions[index] else {
+			continue;
+		};
+		let Some(mut target) = instruction.jump_target(position) else {
+			continue;
+		};
+		// Cycles of jumps (an infinite loop) are followed only once around.
+		let mut visited = HashSet::from([position]);
+		while let Some(&next) = indices.get(&target) {
+			let Some(jump @ Instruction::Jump(_)) = instructions[next].1 else {
+				break;
+			};
+			if !visited.insert(target) {
+				break;
+			}
+			target = jump.jump_target(target).unwrap();
+		}
+
+		let Ok(offset) =
+			JumpOffset::try_from(target as isize - (position + instruction.size()) as isize)
+		else {
+			continue;
+		};
+		instructions[index].1 = if offset == 0 {
+			None
+		} else {
+			match instruction {
+				Instruction::Jump(_) => Some(Instruction::Jump(offset)),
+				_ => Some(Instruction::JumpIfFalse(offset)),
+			}
+		};
+	}
+	relocate(bytecode, &instructions).unwrap_or_else(|| bytecode.clone())
+}
+
+/// Re-emits instructions into a new [`Bytecode`] with the same constants, fixing up jump offsets and function
+/// positions.
+///
+/// `instructions` lists the instructions of the original code along with their original positions, in code order, in
+/// which the removed ones are [`None`] or left out. Jumps and calls still refer to the original positions, and a kept
+/// instruction must have the same size as the original one there. A reference to a removed instruction is redirected
+/// to the next kept instruction, which is what falling through the removed code would do.
+///
+/// Returns [`None`] if a kept instruction refers to a position which isn't the start of a listed instruction (or the
+/// end of the code), or its relocated offset doesn't fit in a [`JumpOffset`].
+pub(super) fn relocate(
+	bytecode: &Bytecode,
+	instructions: &[(usize, Option<Instruction>)],
+) -> Option<Bytecode> {
+	let mut positions = HashMap::new();
+	let mut position = 0;
+	for (original, instruction) in instructions {
+		positions.insert(*original, position);
+		if let Some(instruction) = instruction {
+			position += instruction.size();
+		}
+	}
+	positions.insert(bytecode.code.len(), position);
+	let relocated = |original: usize| positions.get(&original).copied();
+
+	let mut optimized = Bytecode {
+		code: Vec::with_capacity(position),
+		constants: bytecode.constants.clone(),
+	};
+	let mut writer = BytecodeWriter::new(&mut optimized);
+	for (original, instruction) in instructions {
+		let Some(instruction) = *instruction else {
+			continue;
+		};
+		let position = relocated(*original)?;
+		let instruction = match instruction {
+			Instruction::Jump(_) | Instruction::JumpIfFalse(_) => {
+				let target = relocated(instruction.jump_target(*original).unwrap())?;
+				let offset = JumpOffset::try_from(
+					target as isize - (position + instruction.size()) as isize,
+				)
+				.ok()?;
+				match instruction {
+					Instruction::Jump(_) => Instruction::Jump(offset),
+					_ => Instruction::JumpIfFalse(offset),
+				}
+			}
+			Instruction::Fun(entry, arity) => {
+				Instruction::Fun(relocated(entry as usize)? as CallPosition, arity)
+			}
+			Instruction::Closure(entry, arity) => {
+				Instruction::Closure(relocated(entry as usize)? as CallPosition, arity)
+			}
+			Instruction::Call(entry, frame_offset) => {
+				Instruction::Call(relocated(entry as usize)? as CallPosition, frame_offset)
+			}
+			_ => instruction,
+		};
+		writer.emit(instruction);
+	}
+	Some(optimized)
+}
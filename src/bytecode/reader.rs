@@ -1,9 +1,10 @@
 use std::{
 	io::{Cursor, Seek, SeekFrom},
+	marker::PhantomData,
 	mem,
 };
 
-use byteorder::ReadBytesExt;
+use byteorder::{ByteOrder, ReadBytesExt};
 
 use crate::bytecode::{Bytecode, Constant, Endianness, OperationCode};
 
@@ -11,9 +12,12 @@ use crate::bytecode::{Bytecode, Constant, Endianness, OperationCode};
 ///
 /// For operation codes and operands, just call `fetch()`. The offset, endianness and type conversion is considered
 /// internally. For constants, just call `load()`.
-pub struct BytecodeReader<'a> {
+///
+/// Operands are read in the byte order [`E`], which is [`Endianness`] by default.
+pub struct BytecodeReader<'a, E: ByteOrder = Endianness> {
 	cursor: Cursor<&'a Vec<u8>>,
 	constants: &'a Vec<Constant>,
+	endianness: PhantomData<E>,
 }
 
 impl<'a> BytecodeReader<'a> {
@@ -22,9 +26,17 @@ impl<'a> BytecodeReader<'a> {
 	/// BytecodeReader immutably borrows a [`Bytecode`]. That should be easy to optimize and thus get performance
 	/// improvements for Rust compiler.
 	pub fn new(bytecode: &'a Bytecode) -> Self {
+		Self::with_endianness(bytecode)
+	}
+}
+
+impl<'a, E: ByteOrder> BytecodeReader<'a, E> {
+	/// Create a BytecodeReader reading operands in the byte order [`E`].
+	pub fn with_endianness(bytecode: &'a Bytecode) -> Self {
 		Self {
 			cursor: Cursor::new(&bytecode.code),
 			constants: &bytecode.constants,
+			endianness: PhantomData,
 		}
 	}
 
@@ -33,11 +45,32 @@ impl<'a> BytecodeReader<'a> {
 	}
 
 	/// Load a constant if any, panics if index out of bounds.
-	pub fn load(&mut self, index: usize) -> Constant {
+	///
+	/// The constant is borrowed from the [`Bytecode`] rather than cloned, so loading string constants doesn't
+	/// allocate.
+	pub fn load(&self, index: usize) -> &'a Constant {
 		if index >= self.constants.len() {
 			panic!("constant index {} out of bounds", index);
 		}
-		self.constants[index].clone()
+		&self.constants[index]
+	}
+
+	/// Fetches an operation code, or returns [`None`] if there's no valid instruction at the current position, i.e.
+	/// the code ends, the operation code is invalid, or its operands are truncated.
+	///
+	/// Unlike fetching an [`OperationCode`], this never panics, and the operands can be fetched safely afterwards.
+	pub fn fetch_operation(&mut self) -> Option<OperationCode> {
+		let position = self.position();
+		let candidate = *self.cursor.get_ref().get(position)?;
+		if candidate >= OperationCode::Impossible as u8 {
+			return None;
+		}
+		let opcode: OperationCode = unsafe { mem::transmute(candidate) };
+		if position + opcode.size() > self.cursor.get_ref().len() {
+			return None;
+		}
+		self.cursor.set_position(position as u64 + 1);
+		Some(opcode)
 	}
 
 	pub fn jump(&mut self, offset: isize) {
@@ -57,7 +90,7 @@ pub trait Fetch<T> {
 	fn fetch(&mut self) -> T;
 }
 
-impl Fetch<OperationCode> for BytecodeReader<'_> {
+impl<E: ByteOrder> Fetch<OperationCode> for BytecodeReader<'_, E> {
 	fn fetch(&mut self) -> OperationCode {
 		let candidate = self.cursor.read_u8().unwrap();
 		if candidate >= OperationCode::Impossible as u8 {
@@ -67,7 +100,7 @@ impl Fetch<OperationCode> for BytecodeReader<'_> {
 	}
 }
 
-impl Fetch<u8> for BytecodeReader<'_> {
+impl<E: ByteOrder> Fetch<u8> for BytecodeReader<'_, E> {
 	fn fetch(&mut self) -> u8 {
 		self.cursor.read_u8().unwrap()
 	}
@@ -77,9 +110,9 @@ macro_rules! fetch_primitives_impl {
 	($($t: ty), *) => {
 		paste::paste! {
 			$(
-			impl Fetch<$t> for BytecodeReader<'_> {
+			impl<E: ByteOrder> Fetch<$t> for BytecodeReader<'_, E> {
 				fn fetch(&mut self) -> $t {
-					self.cursor.[<read_ $t>]::<Endianness>().unwrap()
+					self.cursor.[<read_ $t>]::<E>().unwrap()
 				}
 			}
 			)*
@@ -0,0 +1,128 @@
+use std::{
+	collections::HashMap,
+	fmt::{Display, Formatter},
+};
+
+use crate::{
+	bytecode::{Bytecode, Constant, OperationCode},
+	vm::LOCALS_CAPACITY,
+};
+
+/// Static statistics of a [`Bytecode`], produced by [`stats`].
+///
+/// Displaying it gives a short report, which helps compiler authors to see where the bytecode bloats.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BytecodeStats {
+	/// The size of the code in bytes.
+	pub code_size: usize,
+	/// The number of instructions.
+	pub instructions: usize,
+	/// How many times each operation code appears, the most frequent first. Absent operation codes are omitted.
+	pub opcodes: Vec<(OperationCode, usize)>,
+	/// The number of number constants.
+	pub numbers: usize,
+	/// The number of string constants.
+	pub strings: usize,
+	/// The total length in bytes of the string constants.
+	pub string_bytes: usize,
+	/// The estimated maximum stack depth of a single call frame, counting its arguments.
+	///
+	/// Nested calls add up at runtime, so the deepest stack a program reaches can be larger.
+	pub max_stack_depth: usize,
+}
+
+/// Collects static statistics of a [`Bytecode`].
+///
+/// The code is decoded up to the first position where there's no valid instruction, so trailing garbage is left out
+/// of the instruction counts instead of panicking, while it's still part of the code size.
+pub fn stats(bytecode: &Bytecode) -> BytecodeStats {
+	let mut instructions = 0;
+	let mut counts: HashMap<OperationCode, usize> = HashMap::new();
+	for (_, instruction) in bytecode.valid_instructions() {
+		instructions += 1;
+		*counts.entry(instruction.opcode()).or_default() += 1;
+	}
+	let mut opcodes: Vec<_> = counts.into_iter().collect();
+	opcodes.sort_by(|(a, x), (b, y)| y.cmp(x).then((*a as u8).cmp(&(*b as u8))));
+
+	let (mut numbers, mut strings, mut string_bytes) = (0, 0, 0);
+	for constant in &bytecode.constants {
+		match constant {
+			Constant::Number(_) => numbers += 1,
+			Constant::String(s) => {
+				strings += 1;
+				string_bytes += s.len();
+			}
+		}
+	}
+
+	BytecodeStats {
+		code_size: bytecode.code.len(),
+		instructions,
+		opcodes,
+		numbers,
+		strings,
+		string_bytes,
+		max_stack_depth: max_stack_depth(bytecode),
+	}
+}
+
+/// Estimates the maximum stack depth of call frames, by walking the control flow from every function entry.
+///
+/// The main function starts with an empty frame, and other functions start with their arguments (the frame offset
+/// of a `Call`, or the arity of a function pointer or closure). Calls are not followed, since the callee is walked
+/// from its own entry.
+fn max_stack_depth(bytecode: &Bytecode) -> usize {
+	let mut entries = vec![(0, 0)];
+	for (_, instruction) in bytecode.valid_instructions() {
+		if let Some((entry, arguments)) = instruction.function_entry() {
+			entries.push((entry, arguments as usize));
+		}
+	}
+
+	let mut max = 0;
+	let mut visited: HashMap<usize, usize> = HashMap::new();
+	let mut worklist = entries;
+	while let Some((position, depth)) = worklist.pop() {
+		// A position reached again with a shallower (or equal) stack adds nothing. Revisiting with a deeper stack is
+		// bounded by the stack capacity, so loops that keep growing the stack terminate as well.
+		if visited.get(&position).is_some_and(|&seen| seen >= depth) || depth > LOCALS_CAPACITY {
+			continue;
+		}
+		visited.insert(position, depth);
+		max = max.max(depth);
+
+		let Some(instruction) = bytecode.instruction_at(position) else {
+			continue;
+		};
+		let depth = depth.saturating_add_signed(instruction.stack_effect());
+		max = max.max(depth);
+		if let Some(target) = instruction.jump_target(position) {
+			worklist.push((target, depth));
+		}
+		if !instruction.is_terminator() {
+			worklist.push((position + instruction.size(), depth));
+		}
+	}
+	max
+}
+
+impl Display for BytecodeStats {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		writeln!(
+			f,
+			"code: {} bytes, {} instructions",
+			self.code_size, self.instructions
+		)?;
+		writeln!(
+			f,
+			"const: {} numbers, {} strings ({} bytes)",
+			self.numbers, self.strings, self.string_bytes
+		)?;
+		writeln!(f, "max stack depth: {}", self.max_stack_depth)?;
+		for (opcode, count) in &self.opcodes {
+			writeln!(f, "\t{:<11} {}", opcode, count)?;
+		}
+		Ok(())
+	}
+}
@@ -1,6 +1,6 @@
-use std::io::Cursor;
+use std::{io::Cursor, marker::PhantomData};
 
-use byteorder::WriteBytesExt;
+use byteorder::{ByteOrder, WriteBytesExt};
 
 use crate::bytecode::{Bytecode, Constant, ConstantIndex, Endianness, OperationCode};
 
@@ -8,10 +8,11 @@ use crate::bytecode::{Bytecode, Constant, ConstantIndex, Endianness, OperationCo
 ///
 /// Supported data (e.g. [`OperationCode`], `u16`, etc.) can be written into bytecode conveniently, without
 /// considering the endianness and how they are turned into `u8` bytes. Internally, [`Cursor`] from the standard
-/// library is used and [`Endianness`] is adopted.
-pub struct BytecodeWriter<'a> {
+/// library is used and [`Endianness`] is adopted by default. Another byte order can be specified by [`E`].
+pub struct BytecodeWriter<'a, E: ByteOrder = Endianness> {
 	cursor: Cursor<&'a mut Vec<u8>>,
 	constants: &'a mut Vec<Constant>,
+	endianness: PhantomData<E>,
 }
 
 impl<'a> BytecodeWriter<'a> {
@@ -20,9 +21,17 @@ impl<'a> BytecodeWriter<'a> {
 	/// BytecodeWriter does not own a [`Bytecode`], it just borrows one, in order to reduce unnecessary moving and
 	/// improve performance.
 	pub fn new(bytecode: &'a mut Bytecode) -> Self {
+		Self::with_endianness(bytecode)
+	}
+}
+
+impl<'a, E: ByteOrder> BytecodeWriter<'a, E> {
+	/// Create a BytecodeWriter writing operands in the byte order [`E`].
+	pub fn with_endianness(bytecode: &'a mut Bytecode) -> Self {
 		Self {
 			cursor: Cursor::new(&mut bytecode.code),
 			constants: &mut bytecode.constants,
+			endianness: PhantomData,
 		}
 	}
 
@@ -46,13 +55,13 @@ pub trait Emit<T> {
 	fn emit(&mut self, value: T);
 }
 
-impl Emit<OperationCode> for BytecodeWriter<'_> {
+impl<E: ByteOrder> Emit<OperationCode> for BytecodeWriter<'_, E> {
 	fn emit(&mut self, value: OperationCode) {
 		self.cursor.write_u8(value as u8).unwrap();
 	}
 }
 
-impl Emit<u8> for BytecodeWriter<'_> {
+impl<E: ByteOrder> Emit<u8> for BytecodeWriter<'_, E> {
 	fn emit(&mut self, value: u8) {
 		self.cursor.write_u8(value).unwrap();
 	}
@@ -62,9 +71,9 @@ macro_rules! emit_primitives_impl {
 	($($t: ty), *) => {
 		paste::paste! {
 			$(
-			impl Emit<$t> for BytecodeWriter<'_> {
+			impl<E: ByteOrder> Emit<$t> for BytecodeWriter<'_, E> {
 				fn emit(&mut self, value: $t)  {
-					self.cursor.[<write_ $t>]::<Endianness>(value).unwrap();
+					self.cursor.[<write_ $t>]::<E>(value).unwrap();
 				}
 			}
 			)*
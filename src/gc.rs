@@ -1,4 +1,14 @@
-use std::collections::HashMap;
+use std::{
+	borrow::Borrow,
+	cell::RefCell,
+	collections::{HashMap, HashSet},
+	error::Error,
+	fmt::{Display, Formatter},
+	hash::{Hash, Hasher},
+	mem,
+	rc::Rc,
+	time::{Duration, Instant},
+};
 
 mod reference;
 mod types;
@@ -8,71 +18,982 @@ pub use types::*;
 
 use crate::value::Value;
 
+/// The heap size in bytes over which the first collection is due, see [`GarbageCollector::should_collect`].
+pub const INITIAL_COLLECTION_THRESHOLD: usize = 1024 * 1024;
+/// How much the heap may grow after a collection before the next one is due, relative to the surviving bytes.
+pub const DEFAULT_GROWTH_FACTOR: f64 = 2.0;
+/// The size in bytes of young allocations over which a minor collection is due, see
+/// [`GarbageCollector::should_collect_nursery`].
+pub const DEFAULT_NURSERY_SIZE: usize = 256 * 1024;
+/// The number of allocations over which the mark phase of a full collection runs on the rayon thread pool, with the
+/// `gc-parallel` feature. Smaller heaps are marked faster on a single thread.
+#[cfg(feature = "gc-parallel")]
+pub const PARALLEL_MARKING_THRESHOLD: usize = 16 * 1024;
+
 pub struct GarbageCollector {
 	allocations: Vec<Reference<()>>,
-	string_pool: HashMap<String, usize>,
+	/// The interned strings. The pool is weak, i.e. it doesn't keep the strings alive, and the dead ones are purged
+	/// when swept.
+	string_pool: HashSet<Interned>,
+	string_pool_stats: StringPoolStats,
+	stats: GcStats,
+	/// The total size of the allocations, see [`Reference::size`]. Only recounted on collections, since the memory
+	/// owned by a value (e.g. the upvalues of a closure) may grow after it's allocated.
+	bytes_allocated: usize,
+	/// The heap size over which the next collection is due.
+	next_collection: usize,
+	/// The lower bound of `next_collection`, so that a small heap isn't collected over and over again.
+	collection_threshold: usize,
+	growth_factor: f64,
+	/// The total size of the young allocations, i.e. those made after the last collection.
+	nursery_bytes: usize,
+	nursery_size: usize,
+	/// The heap size in bytes which fallible allocations may not exceed, see [`GarbageCollector::set_max_heap_size`].
+	max_heap_size: usize,
+	/// The mature allocations which may refer to young ones, see [`GarbageCollector::remember`].
+	remembered: Vec<Reference<()>>,
+	/// The allocations found reachable but not marked yet.
+	gray: Vec<Reference<()>>,
+	/// The pin counts, shared with the [`Pinned`] handles.
+	pins: Rc<PinTable>,
+	/// Whether an incremental collection is in progress.
+	marking: bool,
+	pause_budget: Option<usize>,
+	on_gc: Option<GcCallback>,
+	/// Whether full collections move the survivors, see [`GarbageCollector::set_moving`].
+	moving: bool,
+	/// Where the allocations moved by the last collection are, keyed by their old addresses. It's dropped on the next
+	/// allocation, which may reuse an old address.
+	forwarding: HashMap<usize, Reference<()>>,
+	#[cfg(feature = "gc-diagnostics")]
+	sequence: u64,
+	#[cfg(feature = "gc-diagnostics")]
+	site: Option<usize>,
 }
 
 impl GarbageCollector {
 	pub fn new() -> Self {
 		GarbageCollector {
 			allocations: Vec::new(),
-			string_pool: HashMap::new(),
+			string_pool: HashSet::new(),
+			string_pool_stats: StringPoolStats::default(),
+			stats: GcStats::default(),
+			bytes_allocated: 0,
+			next_collection: INITIAL_COLLECTION_THRESHOLD,
+			collection_threshold: INITIAL_COLLECTION_THRESHOLD,
+			growth_factor: DEFAULT_GROWTH_FACTOR,
+			nursery_bytes: 0,
+			nursery_size: DEFAULT_NURSERY_SIZE,
+			max_heap_size: usize::MAX,
+			remembered: Vec::new(),
+			gray: Vec::new(),
+			pins: Rc::default(),
+			marking: false,
+			pause_budget: None,
+			on_gc: None,
+			moving: false,
+			forwarding: HashMap::new(),
+			#[cfg(feature = "gc-diagnostics")]
+			sequence: 0,
+			#[cfg(feature = "gc-diagnostics")]
+			site: None,
 		}
 	}
 }
 
-impl Drop for GarbageCollector {
-	fn drop(&mut self) {
+/// Live allocations grouped by the bytecode position which made them. See
+/// [`GarbageCollector::allocation_sites`].
+#[cfg(feature = "gc-diagnostics")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllocationSite {
+	/// The bytecode position of the allocating instruction, or [`None`] for allocations made outside the VM.
+	pub site: Option<usize>,
+	/// The number of live allocations made here.
+	pub count: usize,
+	/// The total size in bytes of live allocations made here.
+	pub bytes: usize,
+}
+
+#[cfg(feature = "gc-diagnostics")]
+impl GarbageCollector {
+	/// Sets the bytecode position which the following allocations will be attributed to.
+	pub fn set_allocation_site(&mut self, site: Option<usize>) {
+		self.site = site;
+	}
+
+	/// Returns the number of allocations made so far. The age of an allocation is measured against it.
+	pub fn sequence(&self) -> u64 {
+		self.sequence
+	}
+
+	/// Returns the live allocations grouped by allocation site, the sites holding most live bytes first.
+	///
+	/// This helps to hunt leaks: an instruction that keeps allocating objects which never die will float to the
+	/// top of the report.
+	pub fn allocation_sites(&self) -> Vec<AllocationSite> {
+		use std::collections::HashMap;
+
+		let mut sites: HashMap<Option<usize>, AllocationSite> = HashMap::new();
+		for reference in &self.allocations {
+			let site = reference.origin().site;
+			let entry = sites.entry(site).or_insert(AllocationSite {
+				site,
+				count: 0,
+				bytes: 0,
+			});
+			entry.count += 1;
+			entry.bytes += reference.size();
+		}
+		let mut sites: Vec<_> = sites.into_values().collect();
+		sites.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.site.cmp(&b.site)));
+		sites
+	}
+}
+
+/// How effective string interning is, see [`GarbageCollector::string_pool_stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StringPoolStats {
+	/// The number of interned strings currently alive.
+	pub strings: usize,
+	/// The total length in bytes of the interned strings currently alive.
+	pub bytes: usize,
+	/// How many string allocations were served by an existing interned string.
+	pub hits: u64,
+	/// How many string allocations had to create a new string.
+	pub misses: u64,
+	/// The total length in bytes of the strings which didn't have to be allocated thanks to hits.
+	pub bytes_saved: u64,
+}
+
+/// A fallible allocation would exceed the limit set by [`GarbageCollector::set_max_heap_size`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapExhausted {
+	/// The size in bytes of the allocation.
+	pub size: usize,
+	pub limit: usize,
+}
+
+impl Display for HeapExhausted {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		write!(
+			f,
+			"allocating {} bytes exceeds the heap limit of {} bytes",
+			self.size, self.limit
+		)
+	}
+}
+
+impl Error for HeapExhausted {}
+
+/// Whether a collection pause starts or ends, see [`GarbageCollector::on_gc`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GcEvent {
+	Start,
+	End,
+}
+
+/// The callback set by [`GarbageCollector::on_gc`]. It's shared, so that the settings of a recycled VM keep it.
+type GcCallback = Rc<RefCell<dyn FnMut(GcEvent, GcStats)>>;
+
+/// The tunable settings of a [`GarbageCollector`], i.e. everything its setters change, so that they can be restored
+/// when a VM is recycled.
+#[derive(Clone)]
+pub(crate) struct GcSettings {
+	collection_threshold: usize,
+	growth_factor: f64,
+	nursery_size: usize,
+	max_heap_size: usize,
+	pause_budget: Option<usize>,
+	on_gc: Option<GcCallback>,
+	moving: bool,
+}
+
+/// Heap metrics, see [`GarbageCollector::stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GcStats {
+	/// The number of allocations currently alive.
+	pub objects: usize,
+	/// The total size in bytes of the allocations currently alive, see [`GarbageCollector::bytes_allocated`].
+	pub bytes: usize,
+	/// The total size in bytes of all the allocations ever made.
+	pub total_bytes: u64,
+	/// How many full collections were run, including the incremental ones.
+	pub collections: u64,
+	/// How many minor collections were run.
+	pub minor_collections: u64,
+	/// The total time spent in collections, including every slice of the incremental ones.
+	pub time: Duration,
+	/// The duration of the last pause, i.e. a whole collection, or a slice of an incremental one.
+	pub last_pause: Duration,
+	/// The duration of the longest pause so far.
+	pub max_pause: Duration,
+	/// The number of interned strings currently alive.
+	pub interned_strings: usize,
+}
+
+/// A snapshot of a live allocation, yielded by [`GarbageCollector::iter_objects`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectView {
+	/// The address of the allocation, which identifies it as long as it's alive.
+	pub address: usize,
+	pub kind: AllocationKind,
+	/// The size of the allocation in bytes, see [`Reference::size`].
+	pub size: usize,
+	/// A human-readable rendering of the value, in the same form as the GC trace.
+	pub description: String,
+}
+
+impl Display for ObjectView {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		write!(
+			f,
+			"{:#x} {:?} ({} bytes) {}",
+			self.address, self.kind, self.size, self.description
+		)
+	}
+}
+
+impl Default for GarbageCollector {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl GarbageCollector {
+	/// Pin an allocation, so that it stays alive and never moves until the returned handle is dropped.
+	///
+	/// This is intended for natives handing pointers to foreign code. Pinned allocations are always considered
+	/// reachable, no matter whether the VM still refers to them.
+	pub fn pin<T>(&mut self, reference: Reference<T>) -> Pinned<T> {
+		Pinned::new(reference, &self.pins)
+	}
+
+	/// Pin the allocation a value refers to if any, so that host code can hold the value across allocations (which
+	/// may collect garbage) until the returned handle is dropped. Upvalues are pinned themselves rather than the values
+	/// they box.
+	///
+	/// Same as [`GarbageCollector::pin`], the pin count is kept by the GC, so the handle may be dropped after the GC
+	/// (e.g. along with the [`VirtualMachine`](crate::vm::VirtualMachine) owning it) without touching freed memory.
+	pub fn root(&mut self, value: &Value) -> Option<Pinned<()>> {
+		value
+			.reference()
+			.map(|reference| Pinned::new(reference, &self.pins))
+	}
+
+	/// Returns how many [`Pinned`] handles are currently pinning the allocation.
+	pub fn pins<T>(&self, reference: &Reference<T>) -> usize {
+		self.pins.count(reference.address())
+	}
+
+	/// Returns the total size in bytes of the allocations, as of the last allocation or collection.
+	pub fn bytes_allocated(&self) -> usize {
+		self.bytes_allocated
+	}
+
+	/// Sets the heap size in bytes over which a collection is due, [`INITIAL_COLLECTION_THRESHOLD`] by default.
+	///
+	/// The next collection is due once the heap grows over it. After that, a collection is due when the heap grows by
+	/// the growth factor, but never below this threshold.
+	pub fn set_collection_threshold(&mut self, threshold: usize) {
+		self.collection_threshold = threshold;
+		self.next_collection = threshold;
+	}
+
+	/// Sets how much the heap may grow after a collection before the next one is due, [`DEFAULT_GROWTH_FACTOR`] by
+	/// default. Panics if the factor is less than 1.
+	pub fn set_growth_factor(&mut self, factor: f64) {
+		if factor < 1.0 || factor.is_nan() {
+			panic!("growth factor {} is less than 1", factor);
+		}
+		self.growth_factor = factor;
+	}
+
+	/// Returns whether the heap has grown large enough for a collection.
+	///
+	/// The GC doesn't know the roots itself, so it's up to the owner (i.e. the
+	/// [`VirtualMachine`](crate::vm::VirtualMachine)) to check this before allocating, and run
+	/// [`GarbageCollector::collect`].
+	///
+	/// With the `gc-stress` feature, a collection is always due, so that the VM collects before every allocation. A
+	/// value which isn't properly rooted across an allocation is then freed right away, and caught as a use after
+	/// free, since `gc-stress` enables `gc-validate` as well.
+	pub fn should_collect(&self) -> bool {
+		cfg!(feature = "gc-stress") || self.bytes_allocated > self.next_collection
+	}
+
+	/// Sets the size in bytes of young allocations over which a minor collection is due, [`DEFAULT_NURSERY_SIZE`] by
+	/// default.
+	pub fn set_nursery_size(&mut self, size: usize) {
+		self.nursery_size = size;
+	}
+
+	/// Returns whether enough young allocations are made for a minor collection, see
+	/// [`GarbageCollector::collect_nursery`].
+	pub fn should_collect_nursery(&self) -> bool {
+		self.nursery_bytes > self.nursery_size
+	}
+
+	/// Limits the heap size in bytes, which is unlimited by default. Only the fallible allocations (see
+	/// [`Allocate::try_allocate`] and [`GarbageCollector::try_intern`]) respect the limit, while the others still count
+	/// towards the heap size.
+	///
+	/// The heap size is accounted as [`GarbageCollector::bytes_allocated`] does, so memory that a value grows after
+	/// it's allocated (e.g. the upvalues of a closure) only counts after the next collection.
+	pub fn set_max_heap_size(&mut self, limit: usize) {
+		self.max_heap_size = limit;
+	}
+
+	/// Returns whether `value` can be allocated without exceeding the heap limit. If it can't, a collection may make
+	/// room for it.
+	#[allow(private_bounds)]
+	pub fn fits<T: AllowedAllocationType>(&self, value: &T) -> bool {
+		self.reserve(value.allocation_size()).is_ok()
+	}
+
+	/// Checks an allocation of `size` bytes against the heap limit.
+	fn reserve(&self, size: usize) -> Result<(), HeapExhausted> {
+		match self.bytes_allocated.checked_add(size) {
+			Some(total) if total <= self.max_heap_size => Ok(()),
+			_ => Err(HeapExhausted {
+				size,
+				limit: self.max_heap_size,
+			}),
+		}
+	}
+
+	/// Returns the heap metrics. The counters accumulate over the lifetime of the GC, and are not reset by
+	/// [`GarbageCollector::clear`].
+	pub fn stats(&self) -> GcStats {
+		GcStats {
+			objects: self.allocations.len(),
+			bytes: self.bytes_allocated,
+			interned_strings: self.string_pool.len(),
+			..self.stats
+		}
+	}
+
+	/// Returns the statistics of the string pool. The hit and miss counters accumulate over the lifetime of the GC,
+	/// and are not reset by [`GarbageCollector::clear`].
+	pub fn string_pool_stats(&self) -> StringPoolStats {
+		StringPoolStats {
+			strings: self.string_pool.len(),
+			bytes: self.string_pool.iter().map(|s| s.0.len()).sum(),
+			..self.string_pool_stats
+		}
+	}
+
+	/// Returns the interned string equal to `value` if any, counting the lookup in the pool statistics.
+	fn lookup_interned(&mut self, value: &str) -> Option<Reference<String>> {
+		match self.string_pool.get(value) {
+			Some(interned) => {
+				self.string_pool_stats.hits += 1;
+				self.string_pool_stats.bytes_saved += value.len() as u64;
+				Some(interned.0)
+			}
+			None => {
+				self.string_pool_stats.misses += 1;
+				None
+			}
+		}
+	}
+
+	/// Iterates over the live allocations in allocation order, e.g. to dump the heap or report leaks.
+	pub fn iter_objects(&self) -> impl Iterator<Item = ObjectView> + '_ {
+		self.allocations.iter().map(|reference| ObjectView {
+			address: reference.address(),
+			kind: reference.kind(),
+			size: reference.size(),
+			description: Self::describe(reference),
+		})
+	}
+
+	/// Finalize every allocation that is unreachable, i.e. a full mark-and-sweep collection.
+	///
+	/// An allocation is reachable if it's referred by `roots` (directly or through closures and upvalues), or pinned.
+	/// Interned strings are not kept alive by the pool. It's up to the caller to pass every value it's going to use again
+	/// as roots, since the [`Reference`]s to the finalized allocations are dangling afterwards.
+	///
+	/// The survivors are promoted to the mature generation. If the GC is moving (see
+	/// [`GarbageCollector::set_moving`]), they're moved as well, so the roots must be forwarded afterwards.
+	pub fn collect(&mut self, roots: &[Value]) {
+		self.pause(|gc| {
+			gc.abort_collection();
+			gc.mark(roots, false);
+			gc.propagate(false, usize::MAX);
+			gc.sweep_marked();
+			if gc.moving {
+				gc.relocate();
+			}
+			gc.stats.collections += 1;
+		})
+	}
+
+	/// Sets whether full collections move the survivors, which is off by default.
+	///
+	/// Long-running sessions leave the surviving allocations scattered across the memory freed around them. A moving
+	/// collection reallocates every survivor back to back, which packs them together and lets the allocator reuse
+	/// the gaps, at the cost of copying them. Only [`GarbageCollector::collect`] moves allocations, while minor and
+	/// incremental collections never do. Pinned allocations never move either.
+	///
+	/// Every [`Reference`] held outside the heap is stale after a moving collection, even if it's reachable. It's up
+	/// to the owner of the roots (i.e. the [`VirtualMachine`](crate::vm::VirtualMachine), which does so on its own)
+	/// to update them by [`GarbageCollector::forward`] right after the collection.
+	pub fn set_moving(&mut self, moving: bool) {
+		self.moving = moving;
+	}
+
+	/// Returns whether full collections move the survivors, see [`GarbageCollector::set_moving`].
+	pub fn is_moving(&self) -> bool {
+		self.moving
+	}
+
+	/// Returns where an allocation is moved to by the last collection, or the reference itself if it isn't moved.
+	///
+	/// The forwarding is only kept until the next allocation, which may reuse the address the allocation is moved
+	/// from, so references must be forwarded right after the collection.
+	pub fn forward<T>(&self, reference: Reference<T>) -> Reference<T> {
+		Self::forwarded(&self.forwarding, reference)
+	}
+
+	/// Forwards the reference a value holds if any, see [`GarbageCollector::forward`].
+	pub fn forward_value(&self, value: &mut Value) {
+		Self::forward_in_place(&self.forwarding, value);
+	}
+
+	fn forwarded<T>(
+		forwarding: &HashMap<usize, Reference<()>>,
+		reference: Reference<T>,
+	) -> Reference<T> {
+		match forwarding.get(&reference.address()) {
+			Some(moved) => unsafe { moved.cast() },
+			None => reference,
+		}
+	}
+
+	fn forward_in_place(forwarding: &HashMap<usize, Reference<()>>, value: &mut Value) {
+		match value {
+			Value::String(s) => *s = Self::forwarded(forwarding, *s),
+			Value::FunctionPointer(f) => *f = Self::forwarded(forwarding, *f),
+			Value::Closure(c) => *c = Self::forwarded(forwarding, *c),
+			Value::Upvalue(u) => *u = Self::forwarded(forwarding, *u),
+			Value::Number(_) | Value::Boolean(_) | Value::Nil => {}
+		}
+	}
+
+	/// Moves every allocation but the pinned ones, and forwards the references between them, including those in the
+	/// string pool. The remembered set and the gray list are empty after a full collection, so they're left alone.
+	fn relocate(&mut self) {
+		let mut forwarding = HashMap::with_capacity(self.allocations.len());
 		for reference in &mut self.allocations {
-			#[cfg(feature = "gc-trace")]
-			{
-				macro_rules! trace_reference {
-					(
-						$r: expr,
-						$($variant: ident <$typ: ident $name: ident> => ($($e:expr), +)); *
-						$(;)?
-					) => {
-						match $r.kind() {
-							$(
-							AllocationKind::$variant => {
-								let $name: &$typ = $r.downcast().unwrap();
-								eprint!($($e), *);
-							}
-							)*
-						}
-					};
+			if self.pins.count(reference.address()) > 0 {
+				continue;
+			}
+			let address = reference.address();
+			unsafe { reference.relocate() };
+			forwarding.insert(address, *reference);
+		}
+		for reference in &mut self.allocations {
+			if let Some(closure) = Downcast::<Closure>::downcast_mut(reference) {
+				for upvalue in &mut closure.upvalues {
+					*upvalue = Self::forwarded(&forwarding, *upvalue);
 				}
-				eprint!("=== GC Trace === Dropped <reference at {:p}> ", reference);
-				trace_reference!(
+			} else if let Some(value) = Downcast::<Value>::downcast_mut(reference) {
+				Self::forward_in_place(&forwarding, value);
+			}
+		}
+		self.string_pool = mem::take(&mut self.string_pool)
+			.into_iter()
+			.map(|Interned(s)| Interned(Self::forwarded(&forwarding, s)))
+			.collect();
+		self.forwarding = forwarding;
+	}
+
+	/// Finalize every young allocation that is unreachable, i.e. a minor collection of the nursery.
+	///
+	/// Mature allocations are neither traced nor finalized, so besides `roots`, the young allocations referred by
+	/// mature ones are found through the remembered set, see [`GarbageCollector::remember`]. The survivors are
+	/// promoted to the mature generation, and the mature ones are left for the next [`GarbageCollector::collect`].
+	///
+	/// Like [`GarbageCollector::collect`], the [`Reference`]s to the finalized allocations are dangling afterwards.
+	pub fn collect_nursery(&mut self, roots: &[Value]) {
+		self.pause(|gc| {
+			gc.abort_collection();
+			gc.mark(roots, true);
+			gc.propagate(true, usize::MAX);
+			gc.sweep(|reference| reference.is_mature() || reference.is_marked());
+			gc.stats.minor_collections += 1;
+		})
+	}
+
+	/// Sets the maximum number of allocations marked per slice of an incremental collection, or [`None`] (the
+	/// default) to collect in a single pause.
+	///
+	/// With a budget, a full collection is done incrementally: [`GarbageCollector::start_collection`] begins it,
+	/// [`GarbageCollector::mark_slice`] marks a bounded part of the heap each time, and
+	/// [`GarbageCollector::finish_collection`] ends it. The VM interleaves the slices with the allocating
+	/// instructions.
+	pub fn set_pause_budget(&mut self, budget: Option<usize>) {
+		self.pause_budget = budget;
+	}
+
+	/// Returns the current settings, see [`GarbageCollector::restore_settings`].
+	pub(crate) fn settings(&self) -> GcSettings {
+		GcSettings {
+			collection_threshold: self.collection_threshold,
+			growth_factor: self.growth_factor,
+			nursery_size: self.nursery_size,
+			max_heap_size: self.max_heap_size,
+			pause_budget: self.pause_budget,
+			on_gc: self.on_gc.clone(),
+			moving: self.moving,
+		}
+	}
+
+	/// Restores the settings returned by [`GarbageCollector::settings`] before, undoing every setter called since.
+	pub(crate) fn restore_settings(&mut self, settings: &GcSettings) {
+		self.set_collection_threshold(settings.collection_threshold);
+		self.set_growth_factor(settings.growth_factor);
+		self.set_nursery_size(settings.nursery_size);
+		self.set_max_heap_size(settings.max_heap_size);
+		self.set_pause_budget(settings.pause_budget);
+		self.on_gc = settings.on_gc.clone();
+		self.set_moving(settings.moving);
+	}
+
+	/// Returns whether full collections are done incrementally, see [`GarbageCollector::set_pause_budget`].
+	pub fn is_incremental(&self) -> bool {
+		self.pause_budget.is_some()
+	}
+
+	/// Returns whether an incremental collection is in progress.
+	pub fn is_marking(&self) -> bool {
+		self.marking
+	}
+
+	/// Begins an incremental collection by taking `roots` as the starting point of marking.
+	///
+	/// Until the collection is finished, allocations are considered reachable as soon as they're made, and every
+	/// mutation of a closure or an upvalue must go through [`GarbageCollector::remember`], so that a marked
+	/// allocation doesn't hide an unmarked one from the collector. Any other collection aborts the incremental one.
+	pub fn start_collection(&mut self, roots: &[Value]) {
+		self.pause(|gc| {
+			gc.mark(roots, false);
+			gc.marking = true;
+		})
+	}
+
+	/// Discards the progress of an incremental collection, if any.
+	fn abort_collection(&mut self) {
+		if !self.marking {
+			return;
+		}
+		for reference in &mut self.allocations {
+			reference.set_marked(false);
+		}
+		self.gray.clear();
+		self.marking = false;
+	}
+
+	/// Marks at most as many allocations as the pause budget allows. Returns whether marking is done, i.e. the
+	/// collection is ready to be finished.
+	pub fn mark_slice(&mut self) -> bool {
+		self.pause(|gc| gc.propagate(false, gc.pause_budget.unwrap_or(usize::MAX)))
+	}
+
+	/// Ends an incremental collection: marks whatever is reachable from `roots` and not marked yet, then finalizes
+	/// the unreachable allocations, same as [`GarbageCollector::collect`].
+	///
+	/// The roots (e.g. the VM stack) may have changed since the collection started, so they must be passed again.
+	/// This pause isn't bounded by the budget, but it only marks what's left.
+	pub fn finish_collection(&mut self, roots: &[Value]) {
+		self.pause(|gc| {
+			gc.mark(roots, false);
+			gc.propagate(false, usize::MAX);
+			gc.marking = false;
+			gc.sweep_marked();
+			gc.stats.collections += 1;
+		})
+	}
+
+	/// Sets a callback which is called when every collection pause starts and ends, along with the heap metrics at
+	/// that moment, e.g. to correlate frame hitches with collections. A pause is a whole collection, or a slice of an
+	/// incremental one. The metrics at the end include the pause itself, see [`GcStats::last_pause`].
+	///
+	/// The callback replaces the previous one, if any. It can't touch the GC, since it's called in the middle of a
+	/// collection.
+	pub fn on_gc(&mut self, callback: impl FnMut(GcEvent, GcStats) + 'static) {
+		self.on_gc = Some(Rc::new(RefCell::new(callback)));
+	}
+
+	/// Removes the callback set by [`GarbageCollector::on_gc`].
+	pub fn remove_on_gc(&mut self) {
+		self.on_gc = None;
+	}
+
+	/// Runs a collection pause, timing it and reporting it to the callback, see [`GarbageCollector::on_gc`].
+	///
+	/// With the `tracing` feature, the pause is a span as well.
+	fn pause<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R {
+		#[cfg(feature = "tracing")]
+		let _span = tracing::debug_span!("gc", objects = self.allocations.len()).entered();
+		self.notify(GcEvent::Start);
+		let start = Instant::now();
+		let result = f(self);
+		let pause = start.elapsed();
+		self.stats.time += pause;
+		self.stats.last_pause = pause;
+		self.stats.max_pause = self.stats.max_pause.max(pause);
+		self.notify(GcEvent::End);
+		result
+	}
+
+	fn notify(&self, event: GcEvent) {
+		if let Some(callback) = &self.on_gc {
+			(callback.borrow_mut())(event, self.stats());
+		}
+	}
+
+	/// Records that an allocation is mutated, which is the write barrier of the collector. It must be called
+	/// whenever a reference is stored into a closure or an upvalue (e.g. `SetUpvalue`). Stores into the VM stack and
+	/// globals don't need it, since they're roots.
+	///
+	/// A mature allocation is put into the remembered set, so that the young allocations it refers to are kept by
+	/// minor collections. During an incremental collection, a marked allocation is traced again, so that what it
+	/// refers to now is marked as well.
+	pub fn remember<T>(&mut self, reference: Reference<T>) {
+		let mut reference = unsafe { reference.cast::<()>() };
+		if reference.is_mature() && !reference.is_remembered() {
+			reference.set_remembered(true);
+			self.remembered.push(reference);
+		}
+		if self.marking && reference.is_marked() {
+			Self::trace(&reference, &mut self.gray);
+		}
+	}
+
+	/// Puts the allocations referred by `roots`, the pinned allocations, and those referred by the remembered
+	/// allocations into the gray list, i.e. to be marked.
+	///
+	/// Only a minor collection uses the remembered set up. A full one may be aborted by a minor one before it
+	/// sweeps, which still needs the set, so it's kept until [`GarbageCollector::sweep_marked`].
+	fn mark(&mut self, roots: &[Value], minor: bool) {
+		self.gray.extend(roots.iter().filter_map(Value::reference));
+		self.gray.extend(self.pins.pinned());
+		if minor {
+			for mut reference in self.remembered.drain(..) {
+				reference.set_remembered(false);
+				Self::trace(&reference, &mut self.gray);
+			}
+		} else {
+			for reference in &self.remembered {
+				Self::trace(reference, &mut self.gray);
+			}
+		}
+	}
+
+	/// Marks the allocations in the gray list and what they refer to, until the list is empty or `budget`
+	/// allocations are marked. Returns whether the list is empty. If `minor` is set, mature allocations are taken as
+	/// reachable without tracing.
+	///
+	/// With the `gc-parallel` feature, the unbounded marking of a large heap is done in parallel, see
+	/// [`GarbageCollector::propagate_parallel`].
+	fn propagate(&mut self, minor: bool, mut budget: usize) -> bool {
+		#[cfg(feature = "gc-parallel")]
+		if !minor && budget == usize::MAX && self.allocations.len() > PARALLEL_MARKING_THRESHOLD {
+			self.propagate_parallel();
+			return true;
+		}
+		while let Some(mut reference) = self.gray.pop() {
+			if reference.is_marked() || (minor && reference.is_mature()) {
+				continue;
+			}
+			if budget == 0 {
+				self.gray.push(reference);
+				return false;
+			}
+			budget -= 1;
+			reference.set_marked(true);
+			Self::trace(&reference, &mut self.gray);
+		}
+		true
+	}
+
+	/// Marks the allocations in the gray list and what they refer to on the rayon thread pool, a level of the object
+	/// graph at a time. Only the mark phase runs in parallel, while sweeping stays on the calling thread.
+	#[cfg(feature = "gc-parallel")]
+	fn propagate_parallel(&mut self) {
+		use rayon::prelude::*;
+
+		/// A reference handed to another thread. It's sound since marking only reads the allocations, except for the
+		/// mark bits, which are atomic.
+		struct Shared(Reference<()>);
+
+		unsafe impl Send for Shared {}
+
+		let mut frontier: Vec<_> = self.gray.drain(..).map(Shared).collect();
+		while !frontier.is_empty() {
+			frontier = frontier
+				.into_par_iter()
+				.flat_map_iter(|Shared(reference)| {
+					let mut referred = Vec::new();
+					if reference.try_mark() {
+						Self::trace(&reference, &mut referred);
+					}
+					referred.into_iter().map(Shared)
+				})
+				.collect();
+		}
+	}
+
+	/// Pushes the allocations referred by an allocation into `worklist`.
+	fn trace(reference: &Reference<()>, worklist: &mut Vec<Reference<()>>) {
+		if let Some(closure) = Downcast::<Closure>::downcast(reference) {
+			worklist.extend(
+				closure
+					.upvalues
+					.iter()
+					.map(|upvalue| unsafe { upvalue.cast() }),
+			);
+		} else if let Some(value) = Downcast::<Value>::downcast(reference) {
+			worklist.extend(value.reference());
+		}
+	}
+
+	/// Finalize every allocation that is not pinned (nor referred by a pinned one), no matter whether it's reachable
+	/// or not.
+	///
+	/// After clearing, all the [`Reference`]s handed out before are dangling (including the interned strings),
+	/// except the pinned ones. It's up to the caller to make sure none of them will be used again.
+	pub fn clear(&mut self) {
+		self.abort_collection();
+		for mut reference in self.remembered.drain(..) {
+			reference.set_remembered(false);
+		}
+		self.mark_pinned();
+		self.sweep(|reference| reference.is_marked());
+		self.next_collection = self.collection_threshold;
+	}
+
+	/// Marks the pinned allocations and what they refer to, as the only roots.
+	fn mark_pinned(&mut self) {
+		self.gray.extend(self.pins.pinned());
+		self.propagate(false, usize::MAX);
+	}
+
+	/// Finalize every unmarked allocation, and schedule the next full collection.
+	///
+	/// The remembered set is emptied first, since the survivors are all promoted, so none of them is young anymore.
+	fn sweep_marked(&mut self) {
+		for mut reference in self.remembered.drain(..) {
+			reference.set_remembered(false);
+		}
+		self.sweep(|reference| reference.is_marked());
+		self.next_collection = (self.bytes_allocated as f64 * self.growth_factor)
+			.max(self.collection_threshold as f64) as usize;
+	}
+
+	/// Finalize every allocation that is not to `keep`, and promote the rest to the mature generation.
+	///
+	/// The allocation list is walked as a whole even for minor collections. Only tracing is limited to the nursery.
+	fn sweep(&mut self, keep: impl Fn(&Reference<()>) -> bool) {
+		let (mut live, mut dead): (Vec<_>, Vec<_>) =
+			mem::take(&mut self.allocations).into_iter().partition(keep);
+		for reference in &mut live {
+			reference.set_marked(false);
+			reference.set_mature(true);
+		}
+		self.allocations = live;
+		for reference in &dead {
+			if let Some(s) = Downcast::<String>::downcast(reference) {
+				self.string_pool.remove(s.as_str());
+			}
+		}
+		unsafe { Self::release(&mut dead) };
+		self.bytes_allocated = self.allocations.iter().map(Reference::size).sum();
+		self.nursery_bytes = 0;
+	}
+
+	/// Start tracking a newly spawned allocation.
+	fn track(&mut self, mut allocation: Reference<()>) {
+		if !self.forwarding.is_empty() {
+			self.forwarding = HashMap::new();
+		}
+		#[cfg(feature = "gc-diagnostics")]
+		{
+			allocation.set_origin(Origin {
+				sequence: self.sequence,
+				site: self.site,
+			});
+			self.sequence += 1;
+		}
+		// Allocations made during an incremental collection survive it. What they refer to is marked as well, e.g.
+		// the value boxed by a new upvalue.
+		if self.marking {
+			allocation.set_marked(true);
+			Self::trace(&allocation, &mut self.gray);
+		}
+		let size = allocation.size();
+		self.bytes_allocated += size;
+		self.nursery_bytes += size;
+		self.stats.total_bytes += size as u64;
+		self.allocations.push(allocation);
+	}
+
+	/// Finalize allocations, tracing them if `gc-trace` is enabled.
+	///
+	/// With the `log` feature, the trace goes to the `mussel_vm::gc` target at the trace level instead of stderr, so
+	/// it's controlled by the host's logger regardless of `gc-trace`.
+	///
+	/// Every allocation is traced before any of them is finalized, since describing one may dereference another, e.g.
+	/// an upvalue holding a function pointer.
+	///
+	/// # Safety
+	///
+	/// Same as [`Reference::finalize`].
+	unsafe fn release(references: &mut [Reference<()>]) {
+		#[cfg(any(feature = "gc-trace", feature = "log"))]
+		for reference in references.iter() {
+			#[cfg(feature = "log")]
+			if log::log_enabled!(target: "mussel_vm::gc", log::Level::Trace) {
+				log::trace!(
+					target: "mussel_vm::gc",
+					"dropped <reference at {:p}> {}",
 					reference,
-					String   <String s>          => ("\"{}\"", s);
-					Function <FunctionPointer f> => ("<fun position={:#06X} arity={}>", f.position, f.arity);
-					Closure  <Closure c>         => ("<closure position={:#06X} arity={}>", c.position, c.arity);
-					Upvalue  <Value v>           => ("<upvalue {}>", v);
+					Self::describe(reference)
 				);
-				eprintln!();
 			}
-			unsafe { reference.finalize() };
+			#[cfg(all(feature = "gc-trace", not(feature = "log")))]
+			eprintln!(
+				"=== GC Trace === Dropped <reference at {:p}> {}",
+				reference,
+				Self::describe(reference)
+			);
+		}
+		for reference in references {
+			reference.finalize();
 		}
 	}
+
+	/// Describes an allocation for tracing and heap inspection.
+	fn describe(reference: &Reference<()>) -> String {
+		macro_rules! describe_reference {
+			(
+				$r: expr,
+				$($variant: ident <$typ: ident $name: ident> => ($($e:expr), +)); *
+				$(;)?
+			) => {
+				match $r.kind() {
+					$(
+					AllocationKind::$variant => {
+						let $name: &$typ = $r.downcast().unwrap();
+						format!($($e), *)
+					}
+					)*
+				}
+			};
+		}
+		describe_reference!(
+			reference,
+			String   <String s>          => ("\"{}\"", s);
+			Function <FunctionPointer f> => ("<fun position={:#06X} arity={}>", f.position, f.arity);
+			Closure  <Closure c>         => ("<closure position={:#06X} arity={}>", c.position, c.arity);
+			Upvalue  <Value v>           => ("<upvalue {}>", v);
+		)
+	}
+}
+
+impl Drop for GarbageCollector {
+	/// Finalize every allocation, except those still pinned by handles which outlive the GC. They're leaked along with
+	/// what they refer to, so that the handles never dangle.
+	fn drop(&mut self) {
+		self.abort_collection();
+		self.mark_pinned();
+		let mut dead: Vec<_> = mem::take(&mut self.allocations)
+			.into_iter()
+			.filter(|reference| !reference.is_marked())
+			.collect();
+		unsafe { Self::release(&mut dead) };
+	}
 }
 
 #[allow(private_bounds)]
 pub trait Allocate<T: AllowedAllocationType> {
 	fn allocate(&mut self, value: T) -> Reference<T>;
+
+	/// Allocate a value like [`Allocate::allocate`], unless it would exceed the heap limit, see
+	/// [`GarbageCollector::set_max_heap_size`].
+	fn try_allocate(&mut self, value: T) -> Result<Reference<T>, HeapExhausted>;
+}
+
+impl GarbageCollector {
+	/// Intern a borrowed string, which is only copied if it's not interned yet.
+	pub fn intern(&mut self, value: &str) -> Reference<String> {
+		if let Some(reference) = self.lookup_interned(value) {
+			return reference;
+		}
+		self.spawn_string(value.to_string())
+	}
+
+	/// Intern a borrowed string like [`GarbageCollector::intern`], unless it would exceed the heap limit.
+	pub fn try_intern(&mut self, value: &str) -> Result<Reference<String>, HeapExhausted> {
+		if let Some(reference) = self.lookup_interned(value) {
+			return Ok(reference);
+		}
+		let value = value.to_string();
+		self.reserve(value.allocation_size())?;
+		Ok(self.spawn_string(value))
+	}
+
+	/// Allocate a string which is known to be absent from the pool, and intern it.
+	fn spawn_string(&mut self, value: String) -> Reference<String> {
+		let allocation = unsafe { Reference::spawn(AllocationKind::String, value) };
+		self.string_pool.insert(Interned(allocation));
+		self.track(unsafe { allocation.cast() });
+		allocation
+	}
 }
 
 /// The allocation of [`String`] is specialized because we'll implement String Interning.
 impl Allocate<String> for GarbageCollector {
 	fn allocate(&mut self, value: String) -> Reference<String> {
-		if let Some(index) = self.string_pool.get(&value) {
-			return unsafe { self.allocations[*index].cast() };
+		if let Some(reference) = self.lookup_interned(&value) {
+			return reference;
 		}
-		let allocation = unsafe { Reference::spawn(AllocationKind::String, value.clone()) };
-		self.string_pool.insert(value, self.allocations.len());
-		self.allocations.push(unsafe { allocation.cast() });
-		allocation
+		self.spawn_string(value)
+	}
+
+	fn try_allocate(&mut self, value: String) -> Result<Reference<String>, HeapExhausted> {
+		if let Some(reference) = self.lookup_interned(&value) {
+			return Ok(reference);
+		}
+		self.reserve(value.allocation_size())?;
+		Ok(self.spawn_string(value))
+	}
+}
+
+/// An entry of the string pool, which is hashed and compared by the contents of the string, so that the pool can be
+/// looked up with a borrowed [`str`].
+///
+/// The entries must be removed before the strings are finalized, see [`GarbageCollector::sweep`].
+struct Interned(Reference<String>);
+
+impl Hash for Interned {
+	fn hash<H: Hasher>(&self, state: &mut H) {
+		self.0.as_str().hash(state)
+	}
+}
+
+impl PartialEq for Interned {
+	fn eq(&self, other: &Self) -> bool {
+		self.0.as_str() == other.0.as_str()
+	}
+}
+
+impl Eq for Interned {}
+
+impl Borrow<str> for Interned {
+	fn borrow(&self) -> &str {
+		self.0.as_str()
 	}
 }
 
@@ -83,9 +1004,14 @@ macro_rules! allocate_impl {
 		impl Allocate<$t> for GarbageCollector {
 			fn allocate(&mut self, value: $t) -> Reference<$t> {
 				let allocation = unsafe { Reference::spawn(AllocationKind::$variant, value) };
-				self.allocations.push(unsafe { allocation.cast() });
+				self.track(unsafe { allocation.cast() });
 				allocation
 			}
+
+			fn try_allocate(&mut self, value: $t) -> Result<Reference<$t>, HeapExhausted> {
+				self.reserve(value.allocation_size())?;
+				Ok(self.allocate(value))
+			}
 		}
 		)*
 	};
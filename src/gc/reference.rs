@@ -1,8 +1,13 @@
 use std::{
+	cell::RefCell,
+	collections::HashMap,
 	fmt::Debug,
+	mem,
 	ops::{Deref, DerefMut},
 	ptr,
 	ptr::NonNull,
+	rc::Rc,
+	sync::atomic::{AtomicBool, Ordering},
 };
 
 use crate::{
@@ -11,7 +16,20 @@ use crate::{
 };
 
 /// Helper trait to limit a generic type parameter to a range of GC allowed allocation types.
-pub(super) trait AllowedAllocationType {}
+pub(crate) trait AllowedAllocationType {
+	/// Returns the size of memory owned by the value outside its allocation (e.g. the buffer of a [`String`]).
+	fn owned_size(&self) -> usize {
+		0
+	}
+
+	/// Returns the size of the allocation the value would take, see [`Reference::size`].
+	fn allocation_size(&self) -> usize
+	where
+		Self: Sized,
+	{
+		mem::size_of::<RawAllocation<Self>>() + self.owned_size()
+	}
+}
 
 /// The raw allocation of a specific type.
 ///
@@ -26,9 +44,38 @@ pub(super) trait AllowedAllocationType {}
 #[derive(Debug)]
 struct RawAllocation<T> {
 	kind: AllocationKind,
+	/// Set during the mark phase of a collection if the allocation is reachable, and cleared by the sweep phase.
+	///
+	/// It's atomic so that the mark phase can run on several threads, see the `gc-parallel` feature.
+	marked: AtomicBool,
+	/// Set once the allocation survives a collection.
+	mature: bool,
+	/// Set while the allocation is in the remembered set of the GC.
+	remembered: bool,
+	/// Bumped when the allocation is freed, so that a dangling [`Reference`] is caught on dereferencing.
+	///
+	/// With the `gc-validate` feature, freeing an allocation only drops the value, while the allocation itself is never
+	/// reused (nor returned to the allocator), so reading the generation of a freed allocation is always sound. This
+	/// trades leaking memory for detecting use-after-free deterministically in tests, so it's opt-in.
+	#[cfg(feature = "gc-validate")]
+	generation: u32,
+	#[cfg(feature = "gc-diagnostics")]
+	origin: Origin,
 	value: T,
 }
 
+/// Where and when an allocation is made, recorded for diagnostics.
+#[cfg(feature = "gc-diagnostics")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Origin {
+	/// The sequence number of the allocation, i.e. how many allocations the GC had made before this one. The
+	/// difference between it and [`GarbageCollector::sequence`](crate::gc::GarbageCollector::sequence) is the age of
+	/// the allocation.
+	pub sequence: u64,
+	/// The bytecode position of the instruction which made the allocation, if any.
+	pub site: Option<usize>,
+}
+
 /// A pointer to a chunk of GC allocation.
 ///
 /// This is a thin pointer, which may improve performance when doing value copying in Mussel VM. The pointer points
@@ -45,18 +92,39 @@ impl<T> Reference<T> {
 	/// A helper trait [`AllowedAllocationType`] is applied to limit the value type [`T`] in a valid range. However.
 	/// this function is still marked with `unsafe` because the other parts of code might get the [`AllocationKind`]
 	/// wrong. Check `unsafe` code carefully!
+	///
+	/// # Safety
+	///
+	/// `kind` must be the [`AllocationKind`] registered for [`T`].
 	#[allow(private_bounds)]
 	#[allow(private_interfaces)]
 	pub unsafe fn spawn(kind: AllocationKind, value: T) -> Self
 	where
 		T: AllowedAllocationType,
 	{
-		Self(NonNull::new_unchecked(Box::into_raw(Box::new(RawAllocation { kind, value }))).cast())
+		Self(
+			NonNull::new_unchecked(Box::into_raw(Box::new(RawAllocation {
+				kind,
+				marked: AtomicBool::new(false),
+				mature: false,
+				remembered: false,
+				#[cfg(feature = "gc-validate")]
+				generation: 0,
+				#[cfg(feature = "gc-diagnostics")]
+				origin: Origin::default(),
+				value,
+			})))
+			.cast(),
+		)
 	}
 
 	/// Cast a reference from type [`T`] to type [`U`].
 	///
 	/// This is extremely unsafe since we cannot ensure if the casting is correct.
+	///
+	/// # Safety
+	///
+	/// The allocation must actually hold a [`U`], or [`U`] must be `()` (the type-erased form adopted by GC).
 	pub unsafe fn cast<U>(self) -> Reference<U> {
 		Reference(self.0.cast())
 	}
@@ -68,25 +136,92 @@ impl<T> Reference<T> {
 	pub fn kind(&self) -> AllocationKind {
 		unsafe { self.0.as_ref().kind }
 	}
+
+	/// Returns the address of the allocation (i.e. the object header), which identifies it regardless of [`T`].
+	pub fn address(&self) -> usize {
+		self.0.as_ptr() as usize
+	}
+
+	/// Returns the address of the value itself (i.e. without the object header).
+	///
+	/// The offset of the value depends on its alignment, so this is meaningless on a type-erased `Reference<()>`.
+	/// Downcast it first.
+	pub fn as_ptr(&self) -> *const T {
+		unsafe { &self.0.as_ref().value }
+	}
+
+	/// Returns where and when the allocation is made.
+	#[cfg(feature = "gc-diagnostics")]
+	pub fn origin(&self) -> Origin {
+		unsafe { self.0.as_ref().origin }
+	}
+
+	#[cfg(feature = "gc-diagnostics")]
+	pub(super) fn set_origin(&mut self, origin: Origin) {
+		unsafe { self.0.as_mut().origin = origin };
+	}
+
+	/// Panics if the allocation has been freed. Only checked with the `gc-validate` feature.
+	#[inline]
+	fn validate(&self) {
+		#[cfg(feature = "gc-validate")]
+		if unsafe { self.0.as_ref().generation } != 0 {
+			panic!("use after free of a {:?} allocation", self.kind());
+		}
+	}
+
+	pub(super) fn is_marked(&self) -> bool {
+		unsafe { self.0.as_ref().marked.load(Ordering::Relaxed) }
+	}
+
+	pub(super) fn set_marked(&mut self, marked: bool) {
+		unsafe { self.0.as_ref().marked.store(marked, Ordering::Relaxed) };
+	}
+
+	/// Marks the allocation, and returns whether it wasn't marked before. The allocation may be marked by several
+	/// threads at once, in which case only one of them gets `true`.
+	#[cfg(feature = "gc-parallel")]
+	pub(super) fn try_mark(&self) -> bool {
+		!unsafe { self.0.as_ref().marked.swap(true, Ordering::Relaxed) }
+	}
+
+	/// Returns whether the allocation has survived a collection, i.e. it's in the mature generation.
+	pub fn is_mature(&self) -> bool {
+		unsafe { self.0.as_ref().mature }
+	}
+
+	pub(super) fn set_mature(&mut self, mature: bool) {
+		unsafe { self.0.as_mut().mature = mature };
+	}
+
+	pub(super) fn is_remembered(&self) -> bool {
+		unsafe { self.0.as_ref().remembered }
+	}
+
+	pub(super) fn set_remembered(&mut self, remembered: bool) {
+		unsafe { self.0.as_mut().remembered = remembered };
+	}
 }
 
 impl<T> Deref for Reference<T> {
 	type Target = T;
 
 	fn deref(&self) -> &Self::Target {
+		self.validate();
 		unsafe { &self.0.as_ref().value }
 	}
 }
 
 impl<T> DerefMut for Reference<T> {
 	fn deref_mut(&mut self) -> &mut Self::Target {
+		self.validate();
 		unsafe { &mut self.0.as_mut().value }
 	}
 }
 
 impl<T> Clone for Reference<T> {
 	fn clone(&self) -> Self {
-		Self(self.0)
+		*self
 	}
 }
 
@@ -100,6 +235,101 @@ impl<T, U> PartialEq<Reference<U>> for Reference<T> {
 
 impl<T> Eq for Reference<T> {}
 
+/// A [`Reference`] pinned by the GC, see [`GarbageCollector::pin`](crate::gc::GarbageCollector::pin).
+///
+/// As long as a [`Pinned`] handle is alive, the allocation it refers to is guaranteed to stay alive and keep its
+/// address, so the pointer from [`Pinned::as_ptr`] can be handed to foreign code. The allocation is unpinned when
+/// the last handle is dropped.
+///
+/// Pinning is also how host code holds a [`Reference`] safely: a collection may happen whenever something is allocated,
+/// and the GC only knows about the references the VM holds, so the host has to register its own as roots. See
+/// [`GarbageCollector::root`](crate::gc::GarbageCollector::root) to do so with a [`Value`].
+///
+/// A handle may outlive the [`GarbageCollector`](crate::gc::GarbageCollector). The pin counts are kept by the GC
+/// rather than in the allocations, so a handle never touches its allocation on its own, and the GC leaks what's still
+/// pinned when it's dropped instead of freeing it. The handle stays valid either way.
+#[derive(Debug)]
+pub struct Pinned<T> {
+	reference: Reference<T>,
+	table: Rc<PinTable>,
+}
+
+impl<T> Pinned<T> {
+	pub(super) fn new(reference: Reference<T>, table: &Rc<PinTable>) -> Self {
+		table.pin(unsafe { reference.cast() });
+		Self {
+			reference,
+			table: Rc::clone(table),
+		}
+	}
+
+	/// Returns the pinned reference.
+	pub fn reference(&self) -> Reference<T> {
+		self.reference
+	}
+
+	/// Returns the stable address of the pinned value.
+	pub fn as_ptr(&self) -> *const T {
+		self.reference.as_ptr()
+	}
+}
+
+impl<T> Deref for Pinned<T> {
+	type Target = T;
+
+	fn deref(&self) -> &Self::Target {
+		self.reference.deref()
+	}
+}
+
+impl<T> Clone for Pinned<T> {
+	fn clone(&self) -> Self {
+		Self::new(self.reference, &self.table)
+	}
+}
+
+impl<T> Drop for Pinned<T> {
+	fn drop(&mut self) {
+		self.table.unpin(self.reference.address());
+	}
+}
+
+/// The pin counts of the allocations, keyed by their addresses, see [`Pinned`].
+///
+/// The table is owned by the GC and shared with every handle, so it outlives the allocations.
+#[derive(Debug, Default)]
+pub(super) struct PinTable(RefCell<HashMap<usize, (Reference<()>, usize)>>);
+
+impl PinTable {
+	fn pin(&self, reference: Reference<()>) {
+		let mut pins = self.0.borrow_mut();
+		pins.entry(reference.address()).or_insert((reference, 0)).1 += 1;
+	}
+
+	fn unpin(&self, address: usize) {
+		let mut pins = self.0.borrow_mut();
+		let (_, count) = pins.get_mut(&address).unwrap();
+		*count -= 1;
+		if *count == 0 {
+			pins.remove(&address);
+		}
+	}
+
+	/// Returns how many handles are pinning the allocation at `address`.
+	pub(super) fn count(&self, address: usize) -> usize {
+		self.0.borrow().get(&address).map_or(0, |(_, count)| *count)
+	}
+
+	/// Returns the pinned allocations.
+	pub(super) fn pinned(&self) -> Vec<Reference<()>> {
+		self.0
+			.borrow()
+			.values()
+			.map(|(reference, _)| *reference)
+			.collect()
+	}
+}
+
 /// The helper trait to perform downcasting on a [`Reference`].
 ///
 /// This trait is safe: when the underlying type is [`T`], it returns some reference; otherwise, [`None`] is returned.
@@ -121,18 +351,17 @@ pub trait Downcast<T> {
 macro_rules! register_allowed_types {
 	($($variant: ident => $t: ty); * $(;)?) => {
 		/// The metadata to recognize the actual type of an allocation.
-		#[derive(Debug, Clone, Copy)]
+		#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 		pub enum AllocationKind {
 			$($variant), *
 		}
 
 		$(
-		impl AllowedAllocationType for $t {}
-
 		impl Downcast<$t> for Reference<()> {
 			fn downcast(&self) -> Option<&$t> {
 				match self.kind() {
 					AllocationKind::$variant => {
+						self.validate();
 						let reference = unsafe { self.cast::<$t>() };
 						Some(unsafe { &reference.0.as_ref().value })
 					}
@@ -143,6 +372,7 @@ macro_rules! register_allowed_types {
 			fn downcast_mut(&mut self) -> Option<&mut $t> {
 				match self.kind() {
 					AllocationKind::$variant => {
+						self.validate();
 						let mut reference = unsafe { self.cast::<$t>() };
 						Some(unsafe { &mut reference.0.as_mut().value })
 					}
@@ -153,6 +383,19 @@ macro_rules! register_allowed_types {
 		)*
 
 		impl Reference<()> {
+			/// Returns the size of the allocation in bytes, including the object header and the memory owned by the
+			/// value (e.g. the buffer of a [`String`]).
+			pub fn size(&self) -> usize {
+				match self.kind() {
+					$(
+					AllocationKind::$variant => {
+						let value: &$t = self.downcast().unwrap();
+						value.allocation_size()
+					}
+					)*
+				}
+			}
+
 			/// Finalize a reference.
 			///
 			/// We cannot rely on RAII pattern or borrow checker to clean up the resource, the GC algorithm is
@@ -165,15 +408,53 @@ macro_rules! register_allowed_types {
 			///
 			/// This function is only implemented on `Reference<()>`, because it's the type adopted by GC. Any other
 			/// parts of the program should not finalize any single reference.
+			///
+			/// # Safety
+			///
+			/// The reference must not be finalized twice, and no other copy of it may be dereferenced afterwards.
 			pub unsafe fn finalize(&mut self) {
 				match self.kind() {
 					$(
+					#[cfg(feature = "gc-validate")]
+					AllocationKind::$variant => {
+						let allocation = self.cast::<$t>().0.as_mut();
+						allocation.generation += 1;
+						ptr::drop_in_place(&mut allocation.value);
+					}
+					#[cfg(not(feature = "gc-validate"))]
 					AllocationKind::$variant => {
 						drop(Box::from_raw(self.cast::<$t>().0.as_mut()))
 					}
 					)*
 				}
 			}
+
+			/// Move the allocation to a new one, and free the old one without finalizing the value, see
+			/// [`GarbageCollector::set_moving`](crate::gc::GarbageCollector::set_moving).
+			///
+			/// With the `gc-validate` feature, the old allocation is kept and marked as freed instead, so that a
+			/// reference which isn't forwarded is caught as a use after free.
+			///
+			/// # Safety
+			///
+			/// No other copy of the reference may be dereferenced afterwards, unless it's forwarded to this one.
+			pub(super) unsafe fn relocate(&mut self) {
+				match self.kind() {
+					$(
+					AllocationKind::$variant => {
+						let old = self.cast::<$t>().0.as_ptr();
+						let moved = Box::into_raw(Box::new(ptr::read(old)));
+						#[cfg(feature = "gc-validate")]
+						{
+							(*old).generation += 1;
+						}
+						#[cfg(not(feature = "gc-validate"))]
+						drop(Box::from_raw(old.cast::<mem::ManuallyDrop<RawAllocation<$t>>>()));
+						*self = Reference(NonNull::new_unchecked(moved)).cast();
+					}
+					)*
+				}
+			}
 		}
 	};
 }
@@ -1,6 +1,8 @@
+use std::mem;
+
 use crate::{
 	bytecode::{CallPosition, LocalOffset},
-	gc::Reference,
+	gc::{reference::AllowedAllocationType, Reference},
 	value::Value,
 };
 
@@ -16,3 +18,19 @@ pub struct Closure {
 	pub arity: LocalOffset,
 	pub upvalues: Vec<Reference<Value>>,
 }
+
+impl AllowedAllocationType for String {
+	fn owned_size(&self) -> usize {
+		self.capacity()
+	}
+}
+
+impl AllowedAllocationType for FunctionPointer {}
+
+impl AllowedAllocationType for Closure {
+	fn owned_size(&self) -> usize {
+		self.upvalues.capacity() * mem::size_of::<Reference<Value>>()
+	}
+}
+
+impl AllowedAllocationType for Value {}
@@ -1,10 +1,15 @@
+use std::process::ExitCode;
+
 use mussel_vm::{
 	bytecode,
 	bytecode::{CallPosition, Constant, ConstantIndex, GlobalIndex, LocalOffset, OperationCode},
 	vm::VirtualMachine,
 };
 
-fn main() {
+/// The exit code of a program aborted by a runtime error, following `EX_SOFTWARE` of `sysexits.h`.
+const RUNTIME_ERROR: u8 = 70;
+
+fn main() -> ExitCode {
 	let bytecode = bytecode! {
 		// fun hello() {
 		// 	var world = 1;
@@ -100,5 +105,13 @@ fn main() {
 	};
 
 	let mut vm = VirtualMachine::new();
-	vm.interpret(&bytecode);
+	let interrupt = vm.interrupt_handle();
+	ctrlc::set_handler(move || interrupt.interrupt()).expect("cannot set the Ctrl-C handler");
+	match vm.interpret(&bytecode) {
+		Ok(()) => ExitCode::SUCCESS,
+		Err(error) => {
+			eprintln!("runtime error: {}", error);
+			ExitCode::from(RUNTIME_ERROR)
+		}
+	}
 }
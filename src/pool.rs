@@ -0,0 +1,106 @@
+use std::{
+	cell::RefCell,
+	ops::{Deref, DerefMut},
+};
+
+use crate::vm::{VirtualMachine, VmSettings};
+
+/// A pool of ready-to-use [`VirtualMachine`]s.
+///
+/// Creating a VM is not free (e.g. the globals are preallocated), which matters when an embedder runs one script
+/// per request. A [`VmPool`] creates VMs ahead of time, hands them out by [`VmPool::acquire`], and takes them back
+/// automatically when the [`PooledVm`] guard is dropped. Returned VMs are fully recycled, so nothing leaks from one
+/// job to the next: the heap is reset (see [`VirtualMachine::reset_heap`]), loaded programs are dropped, a pending
+/// interrupt is cleared, and the limits and GC settings are restored to those the VM was created with.
+pub struct VmPool {
+	/// The idle VMs, along with their settings as created.
+	idle: RefCell<Vec<(VirtualMachine, VmSettings)>>,
+	capacity: usize,
+	factory: Box<dyn Fn() -> VirtualMachine>,
+}
+
+impl VmPool {
+	/// Create a pool with `capacity` VMs created by [`VirtualMachine::new`].
+	pub fn new(capacity: usize) -> Self {
+		Self::with_factory(capacity, VirtualMachine::new)
+	}
+
+	/// Create a pool with `capacity` VMs created by `factory`.
+	///
+	/// The factory is where VMs get configured. It's also called when the pool runs out of idle VMs, in which case
+	/// the extra VM is dropped instead of being kept when it's returned to a full pool.
+	pub fn with_factory<F>(capacity: usize, factory: F) -> Self
+	where
+		F: Fn() -> VirtualMachine + 'static,
+	{
+		let idle = (0..capacity).map(|_| create(&factory)).collect();
+		Self {
+			idle: RefCell::new(idle),
+			capacity,
+			factory: Box::new(factory),
+		}
+	}
+
+	/// Take a VM out of the pool, creating a new one if there's no idle VM.
+	pub fn acquire(&self) -> PooledVm<'_> {
+		let (vm, settings) = self
+			.idle
+			.borrow_mut()
+			.pop()
+			.unwrap_or_else(|| create(&self.factory));
+		PooledVm {
+			pool: self,
+			vm: Some(vm),
+			settings,
+		}
+	}
+
+	/// Returns the number of idle VMs in the pool.
+	pub fn idle(&self) -> usize {
+		self.idle.borrow().len()
+	}
+
+	fn release(&self, mut vm: VirtualMachine, settings: VmSettings) {
+		vm.recycle(&settings);
+		let mut idle = self.idle.borrow_mut();
+		if idle.len() < self.capacity {
+			idle.push((vm, settings));
+		}
+	}
+}
+
+/// Creates a VM by `factory`, taking its settings as configured there.
+fn create(factory: &dyn Fn() -> VirtualMachine) -> (VirtualMachine, VmSettings) {
+	let vm = factory();
+	let settings = vm.settings();
+	(vm, settings)
+}
+
+/// A [`VirtualMachine`] borrowed from a [`VmPool`], which is returned to the pool on drop.
+pub struct PooledVm<'a> {
+	pool: &'a VmPool,
+	vm: Option<VirtualMachine>,
+	settings: VmSettings,
+}
+
+impl Deref for PooledVm<'_> {
+	type Target = VirtualMachine;
+
+	fn deref(&self) -> &Self::Target {
+		self.vm.as_ref().unwrap()
+	}
+}
+
+impl DerefMut for PooledVm<'_> {
+	fn deref_mut(&mut self) -> &mut Self::Target {
+		self.vm.as_mut().unwrap()
+	}
+}
+
+impl Drop for PooledVm<'_> {
+	fn drop(&mut self) {
+		if let Some(vm) = self.vm.take() {
+			self.pool.release(vm, self.settings.clone());
+		}
+	}
+}
@@ -86,13 +86,19 @@ impl<T, const N: usize> Stack<T, N> {
 	/// Since we're using [`MaybeUninit`], we just call `assume_init_drop` to drop the elements in-place. This should
 	/// save some memory cost :).
 	pub fn clear(&mut self) {
-		for element in (&mut self.elements[..self.top]).into_iter().rev() {
+		for element in self.elements[..self.top].iter_mut().rev() {
 			unsafe { element.assume_init_drop() };
 		}
 		self.top = 0;
 	}
 }
 
+impl<T, const N: usize> Default for Stack<T, N> {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
 impl<T, const N: usize> Drop for Stack<T, N> {
 	fn drop(&mut self) {
 		self.clear()
@@ -118,7 +124,7 @@ impl<'a, T, const N: usize> IntoIterator for &'a Stack<T, N> {
 	type IntoIter = Iter<'a, T>;
 
 	fn into_iter(self) -> Self::IntoIter {
-		self.deref().into_iter()
+		self.deref().iter()
 	}
 }
 
@@ -127,7 +133,7 @@ impl<'a, T, const N: usize> IntoIterator for &'a mut Stack<T, N> {
 	type IntoIter = IterMut<'a, T>;
 
 	fn into_iter(self) -> Self::IntoIter {
-		self.deref_mut().into_iter()
+		self.deref_mut().iter_mut()
 	}
 }
 
@@ -20,7 +20,69 @@ pub enum Value {
 	Upvalue(Reference<Value>),
 }
 
+/// A value owned by the host, independent of any VM or GC, so it can be kept after the VM is dropped.
+///
+/// Only data converts to an owned value. Functions and closures refer to code and captured state inside a VM, which
+/// are meaningless outside of it. See [`VirtualMachine::extract`](crate::vm::VirtualMachine::extract) and
+/// [`VirtualMachine::inject`](crate::vm::VirtualMachine::inject).
+#[derive(Debug, Clone, PartialEq)]
+pub enum OwnedValue {
+	Number(f64),
+	Boolean(bool),
+	Nil,
+	String(String),
+}
+
+impl From<f64> for OwnedValue {
+	fn from(n: f64) -> Self {
+		OwnedValue::Number(n)
+	}
+}
+
+impl From<bool> for OwnedValue {
+	fn from(b: bool) -> Self {
+		OwnedValue::Boolean(b)
+	}
+}
+
+impl From<String> for OwnedValue {
+	fn from(s: String) -> Self {
+		OwnedValue::String(s)
+	}
+}
+
+impl From<&str> for OwnedValue {
+	fn from(s: &str) -> Self {
+		OwnedValue::String(s.to_string())
+	}
+}
+
 impl Value {
+	/// Returns the value itself, or the boxed value if this is an upvalue.
+	///
+	/// Local variables captured by closures are boxed in place (see
+	/// [`OperationCode::Capture`](crate::bytecode::OperationCode::Capture)), but the boxing should never be visible
+	/// to the program. Everywhere a variable is read, the value should be unboxed first.
+	pub fn unbox(&self) -> Value {
+		match self {
+			Value::Upvalue(u) => u.deref().clone(),
+			_ => self.clone(),
+		}
+	}
+
+	/// Returns the heap allocation the value refers to, or [`None`] for primitive values.
+	pub fn reference(&self) -> Option<Reference<()>> {
+		unsafe {
+			match self {
+				Value::String(s) => Some(s.cast()),
+				Value::FunctionPointer(f) => Some(f.cast()),
+				Value::Closure(c) => Some(c.cast()),
+				Value::Upvalue(u) => Some(u.cast()),
+				_ => None,
+			}
+		}
+	}
+
 	pub fn as_boolean(&self) -> bool {
 		match self {
 			Value::Boolean(b) => *b,
@@ -30,9 +92,22 @@ impl Value {
 	}
 }
 
+/// Equality of values, as observed by [`OperationCode::Equal`](crate::bytecode::OperationCode::Equal).
+///
+/// Primitive values (numbers, booleans and nil) and strings are compared by their contents. Callable objects are
+/// compared by identity instead:
+///
+/// - A function pointer identifies a function, so two function pointers are equal when they refer to the same
+///   function entry (i.e. the same position and arity), even if they are created by different `Fun` instructions.
+/// - A closure carries its own captured upvalues, so two closures are equal only if they are the very same object.
+///   A closure is never equal to a function pointer, even if they share the same entry position.
+///
+/// Upvalues are transparent: a boxed value is compared as the value it holds.
 impl PartialEq for Value {
 	fn eq(&self, other: &Self) -> bool {
 		match (self, other) {
+			(Value::Upvalue(u), other) => u.deref() == other,
+			(this, Value::Upvalue(u)) => this == u.deref(),
 			(Value::Number(n1), Value::Number(n2)) => (n1 - n2).abs() < f64::EPSILON,
 			(Value::Boolean(b1), Value::Boolean(b2)) => b1 == b2,
 			(Value::Nil, Value::Nil) => true,
@@ -54,6 +129,8 @@ impl PartialEq for Value {
 	}
 }
 
+/// Displays a value as `Print` does. Upvalues never reach `Print` since reading a variable unboxes it, so they're
+/// displayed as `<upvalue inner>` to tell them apart when debugging.
 impl Display for Value {
 	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
 		match self {
@@ -73,7 +150,7 @@ impl Display for Value {
 				"<closure position={:#06X} arity={}>",
 				c.position, c.arity
 			),
-			Value::Upvalue(u) => u.deref().fmt(f),
+			Value::Upvalue(u) => write!(f, "<upvalue {}>", u.deref()),
 		}
 	}
 }
@@ -1,22 +1,105 @@
-use std::{mem, ops::Deref};
+use std::{
+	collections::HashMap,
+	ops::Deref,
+	rc::Rc,
+	sync::{
+		atomic::{AtomicBool, AtomicUsize, Ordering},
+		Arc,
+	},
+};
+
+use byteorder::ByteOrder;
 
 use crate::{
 	bytecode::{
-		Bytecode, BytecodeReader, CallPosition, Constant, ConstantIndex, Fetch, GlobalIndex,
+		Bytecode, BytecodeReader, CallPosition, ConstantIndex, Endianness, Fetch, GlobalIndex,
 		JumpOffset, LocalOffset, OperationCode,
 	},
-	gc::{Allocate, Closure, FunctionPointer, GarbageCollector, Reference},
+	gc::{Allocate, AllowedAllocationType, Closure, GarbageCollector, GcSettings, Reference},
 	stack::Stack,
-	value::Value,
+	value::{OwnedValue, Value},
 };
 
+mod error;
+mod handlers;
+
+pub use error::*;
+
 pub const GLOBALS_CAPACITY: usize = GlobalIndex::MAX as usize + 1;
 pub const LOCALS_CAPACITY: usize = LocalOffset::MAX as usize + 1;
+/// The maximum depth of nested function calls by default, see [`VirtualMachine::set_max_call_depth`].
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 256;
 
 struct CallFrame {
 	position: CallPosition,
-	frame: LocalOffset,
+	frame: usize,
 	closure: Option<Reference<Closure>>,
+	function: Option<CallPosition>,
+	/// The position of the calling instruction, kept for stack traces.
+	call: usize,
+}
+
+/// A handle to interrupt a running [`VirtualMachine`], e.g. from a signal handler or another thread.
+///
+/// The VM polls it at its safepoints, i.e. backward jumps and calls, so every loop and recursion notices a request
+/// soon, while straight-line code is never slowed down.
+#[derive(Debug, Clone, Default)]
+pub struct InterruptHandle(Arc<Requests>);
+
+#[derive(Debug, Default)]
+struct Requests {
+	interrupt: AtomicBool,
+	collection: AtomicBool,
+}
+
+impl InterruptHandle {
+	/// Requests the VM to stop. The VM aborts with [`RuntimeErrorKind::Interrupted`] once it notices the request.
+	pub fn interrupt(&self) {
+		self.0.interrupt.store(true, Ordering::Relaxed);
+	}
+
+	/// Requests the VM to run a full collection at its next safepoint, even if the heap hasn't grown large enough.
+	///
+	/// This lets a script which holds a large dead structure while looping without allocating give the memory back.
+	/// An idle VM can be collected by [`VirtualMachine::collect_garbage`] directly.
+	pub fn request_collection(&self) {
+		self.0.collection.store(true, Ordering::Relaxed);
+	}
+}
+
+/// The settings of a [`VirtualMachine`] which a host tunes before running scripts, along with those of its GC, so
+/// that they can be restored when the VM is recycled.
+#[derive(Clone)]
+pub(crate) struct VmSettings {
+	max_string_length: usize,
+	max_call_depth: usize,
+	gc: GcSettings,
+}
+
+/// A program loaded into a [`VirtualMachine`], see [`VirtualMachine::load`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ProgramHandle {
+	/// The [`VirtualMachine::programs_id`] of the VM which loaded the program.
+	programs_id: usize,
+	index: usize,
+}
+
+/// The next [`VirtualMachine::programs_id`] to hand out.
+static NEXT_PROGRAMS_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A resident program, along with the positions of its functions which the host can call by name.
+struct Program {
+	bytecode: Bytecode,
+	entries: HashMap<String, CallPosition>,
+}
+
+/// The spans of the calls being executed, see [`VirtualMachine::enter_span`].
+#[cfg(feature = "tracing")]
+#[derive(Default)]
+struct CallSpans {
+	/// The program being run, whose entry points name the spans, if it's a loaded one.
+	program: Option<Rc<Program>>,
+	open: Vec<tracing::span::EnteredSpan>,
 }
 
 /// The Mussel VM.
@@ -27,9 +110,38 @@ pub struct VirtualMachine {
 	globals: Vec<Value>,
 	stack: Stack<Value, LOCALS_CAPACITY>,
 	gc: GarbageCollector,
-	frame: LocalOffset,
+	/// The stack index where the current call frame starts.
+	///
+	/// [`LocalOffset`] is only the encoding type of operands. The frame itself is kept in `usize`, since a frame can
+	/// start at the very end of the stack, whose length can be [`LOCALS_CAPACITY`].
+	frame: usize,
 	closure: Option<Reference<Closure>>,
+	/// The entry position of the function being executed, or [`None`] at the top level.
+	function: Option<CallPosition>,
+	/// The call frames of outer functions. It's preallocated up to the max call depth, so that deep recursion never
+	/// reallocates and infinite recursion is caught.
 	callstack: Vec<CallFrame>,
+	/// The maximum depth of nested function calls, see [`VirtualMachine::set_max_call_depth`].
+	max_call_depth: usize,
+	/// The interned string constants of the bytecode being executed, indexed by [`ConstantIndex`] and populated
+	/// lazily, so that a string constant is cloned and hashed only once per [`VirtualMachine::interpret`].
+	strings: Vec<Option<Reference<String>>>,
+	interrupt: InterruptHandle,
+	/// The maximum length in bytes of a single string, see [`VirtualMachine::set_max_string_length`].
+	max_string_length: usize,
+	/// The resident programs, indexed by [`ProgramHandle`].
+	programs: Vec<Rc<Program>>,
+	/// Tells the handles to the resident programs apart from those of other VMs, or of the programs unloaded by
+	/// [`VirtualMachine::recycle`], which get another id.
+	programs_id: usize,
+	#[cfg(feature = "tracing")]
+	spans: CallSpans,
+}
+
+impl Default for VirtualMachine {
+	fn default() -> Self {
+		Self::new()
+	}
 }
 
 impl VirtualMachine {
@@ -41,300 +153,632 @@ impl VirtualMachine {
 			gc: GarbageCollector::new(),
 			frame: 0,
 			closure: None,
-			callstack: Vec::new(),
+			function: None,
+			callstack: Vec::with_capacity(DEFAULT_MAX_CALL_DEPTH),
+			max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+			strings: Vec::new(),
+			interrupt: InterruptHandle::default(),
+			max_string_length: usize::MAX,
+			programs: Vec::new(),
+			programs_id: NEXT_PROGRAMS_ID.fetch_add(1, Ordering::Relaxed),
+			#[cfg(feature = "tracing")]
+			spans: CallSpans::default(),
 		}
 	}
 
-	/// Reset the program states, as if the VM is just created and ready to execute bytecode.
+	/// Reset the program states, as if the VM is just created and ready to execute bytecode. Pending requests of the
+	/// [`InterruptHandle`] are dropped as well, since they were meant for the previous execution.
 	///
-	/// Note that GC is not reset here, it's up to itself to collect garbage.
+	/// Note that GC is not reset here, it's up to itself to collect garbage. See [`VirtualMachine::reset_heap`] to
+	/// drop the heap allocations as well.
 	pub fn reset(&mut self) {
 		self.globals.fill(Value::Nil);
 		self.stack.clear();
 		self.frame = 0;
+		self.closure = None;
+		self.function = None;
 		self.callstack.clear();
+		self.strings.clear();
+		self.interrupt.0.interrupt.store(false, Ordering::Relaxed);
+		self.interrupt.0.collection.store(false, Ordering::Relaxed);
+	}
+
+	/// Reset the program states, and drop every heap allocation (including the interned strings) as well.
+	///
+	/// This fully recycles a VM, e.g. when the same VM is reused across jobs. Since the program states are reset
+	/// first, nothing inside the VM refers to the heap anymore. However, [`Value`]s the host got out of the VM before
+	/// (e.g. by [`VirtualMachine::global`]) are dangling afterwards and must not be used, unless they're pinned (see
+	/// [`GarbageCollector::pin`]).
+	pub fn reset_heap(&mut self) {
+		self.reset();
+		self.gc.clear();
+	}
+
+	/// Returns the current settings, see [`VirtualMachine::recycle`].
+	pub(crate) fn settings(&self) -> VmSettings {
+		VmSettings {
+			max_string_length: self.max_string_length,
+			max_call_depth: self.max_call_depth,
+			gc: self.gc.settings(),
+		}
+	}
+
+	/// Recycle the VM for an unrelated job: reset the heap (see [`VirtualMachine::reset_heap`]), unload every program,
+	/// and restore `settings` taken by [`VirtualMachine::settings`] before, so that nothing a job does to the VM carries
+	/// over to the next one.
+	pub(crate) fn recycle(&mut self, settings: &VmSettings) {
+		self.reset_heap();
+		self.programs.clear();
+		self.programs_id = NEXT_PROGRAMS_ID.fetch_add(1, Ordering::Relaxed);
+		self.max_string_length = settings.max_string_length;
+		self.set_max_call_depth(settings.max_call_depth);
+		self.gc.restore_settings(&settings.gc);
+	}
+
+	/// Returns the global variable at `index`, unboxed if it's captured as an upvalue.
+	///
+	/// This is mostly useful for the host to inspect the program states after executing some bytecode.
+	pub fn global(&self, index: GlobalIndex) -> Value {
+		self.globals[index as usize].unbox()
+	}
+
+	/// Copies a value out of the VM into an [`OwnedValue`], which stays valid after the VM (or its heap) is gone.
+	///
+	/// Upvalues are unboxed first. Returns [`None`] for functions and closures, which cannot live outside the VM.
+	pub fn extract(&self, value: &Value) -> Option<OwnedValue> {
+		match value.unbox() {
+			Value::Number(n) => Some(OwnedValue::Number(n)),
+			Value::Boolean(b) => Some(OwnedValue::Boolean(b)),
+			Value::Nil => Some(OwnedValue::Nil),
+			Value::String(s) => Some(OwnedValue::String(s.deref().clone())),
+			Value::FunctionPointer(_) | Value::Closure(_) => None,
+			Value::Upvalue(_) => unreachable!("upvalues are never nested"),
+		}
+	}
+
+	/// Converts a host value into a VM value, interning strings into the VM's heap.
+	pub fn inject(&mut self, value: OwnedValue) -> Value {
+		match value {
+			OwnedValue::Number(n) => Value::Number(n),
+			OwnedValue::Boolean(b) => Value::Boolean(b),
+			OwnedValue::Nil => Value::Nil,
+			OwnedValue::String(s) => Value::String(self.gc.allocate(s)),
+		}
+	}
+
+	/// Sets the global variable at `index`, through the upvalue if it's captured, e.g. to hand a host value to the
+	/// script before executing it. An upvalue is unboxed first, so that it's never boxed into another one.
+	pub fn set_global(&mut self, index: GlobalIndex, value: Value) {
+		let value = value.unbox();
+		let target = &mut self.globals[index as usize];
+		if let Value::Upvalue(u) = target {
+			**u = value;
+			self.gc.remember(*u);
+		} else {
+			*target = value;
+		}
+	}
+
+	/// Returns the stack, whose bottom values are the locals of the top-level code.
+	///
+	/// Like [`VirtualMachine::global`], this is mostly useful for the host to inspect the program states.
+	pub fn stack(&self) -> &Stack<Value, LOCALS_CAPACITY> {
+		&self.stack
+	}
+
+	/// Returns the stack mutably, e.g. to set up the locals of the top-level code before executing some bytecode.
+	///
+	/// Values pushed by the host are reachable by the GC, same as those pushed by instructions.
+	pub fn stack_mut(&mut self) -> &mut Stack<Value, LOCALS_CAPACITY> {
+		&mut self.stack
+	}
+
+	/// Returns the garbage collector of the VM, e.g. to inspect the heap.
+	pub fn gc(&self) -> &GarbageCollector {
+		&self.gc
+	}
+
+	/// Returns the garbage collector of the VM mutably, e.g. to tune when collections happen.
+	pub fn gc_mut(&mut self) -> &mut GarbageCollector {
+		&mut self.gc
+	}
+
+	/// Keeps a program resident in the VM, so that it can be run repeatedly by the returned handle.
+	///
+	/// Programs are code rather than program states, so they're kept across [`VirtualMachine::reset`] and
+	/// [`VirtualMachine::reset_heap`]. All the programs share the same globals and heap.
+	pub fn load(&mut self, bytecode: Bytecode) -> ProgramHandle {
+		self.load_with_entries(bytecode, std::iter::empty::<(String, CallPosition)>())
+	}
+
+	/// Keeps a program resident like [`VirtualMachine::load`], along with the entry positions of the functions which
+	/// can be called by name, see [`VirtualMachine::call`]. The bytecode itself doesn't name its functions, so the
+	/// names come from whatever produced it, e.g. a compiler.
+	pub fn load_with_entries<S: Into<String>>(
+		&mut self,
+		bytecode: Bytecode,
+		entries: impl IntoIterator<Item = (S, CallPosition)>,
+	) -> ProgramHandle {
+		let entries = entries
+			.into_iter()
+			.map(|(name, entry)| (name.into(), entry))
+			.collect();
+		self.programs.push(Rc::new(Program { bytecode, entries }));
+		ProgramHandle {
+			programs_id: self.programs_id,
+			index: self.programs.len() - 1,
+		}
+	}
+
+	/// Returns the loaded program behind a handle. Panics if the handle is from another VM.
+	fn program(&self, handle: ProgramHandle) -> Rc<Program> {
+		if handle.programs_id != self.programs_id {
+			panic!("program {} is loaded into another VM", handle.index);
+		}
+		Rc::clone(&self.programs[handle.index])
+	}
+
+	/// Executes a program loaded by [`VirtualMachine::load`] from its beginning, same as
+	/// [`VirtualMachine::interpret`] does.
+	///
+	/// Panics if the handle is from another VM.
+	pub fn run(&mut self, handle: ProgramHandle) -> Result<(), RuntimeError> {
+		let program = self.program(handle);
+		self.name_spans(Some(&program));
+		let result = self.interpret(&program.bytecode);
+		self.name_spans(None);
+		result
+	}
+
+	/// Calls a function of a loaded program by the name given to [`VirtualMachine::load_with_entries`], passing
+	/// `arguments` as its parameters, and returns what the function returns.
+	///
+	/// The function runs on top of the current program states, e.g. the globals set by [`VirtualMachine::run`], and
+	/// its locals are dropped once it returns. It's called from the top level, so the VM must not be in the middle of
+	/// an execution. Same as [`VirtualMachine::interpret`], the VM is left as it was on a [`RuntimeError`], and must
+	/// be reset before calling anything else. An unknown name is a
+	/// [`RuntimeErrorKind::UnknownEntry`] error, reported before anything is executed.
+	///
+	/// Panics if the handle is from another VM.
+	pub fn call(
+		&mut self,
+		handle: ProgramHandle,
+		name: &str,
+		arguments: &[Value],
+	) -> Result<Value, RuntimeError> {
+		let program = self.program(handle);
+		let Some(&entry) = program.entries.get(name) else {
+			return Err(RuntimeError {
+				kind: RuntimeErrorKind::UnknownEntry(name.to_string()),
+				position: 0,
+				trace: Vec::new(),
+			});
+		};
+
+		let frame = self.stack.len();
+		let outer = (self.frame, self.function.replace(entry));
+		self.frame = frame;
+		self.closure = None;
+		let mut position = entry as usize;
+		self.name_spans(Some(&program));
+		self.enter_span(entry);
+		let result = arguments
+			.iter()
+			.try_for_each(|argument| self.push(argument.unbox()))
+			.and_then(|_| self.execute::<Endianness>(&program.bytecode, &mut position));
+		self.exit_spans();
+		self.name_spans(None);
+		if let Err(kind) = result {
+			return Err(RuntimeError {
+				kind,
+				position,
+				trace: self.trace(position),
+			});
+		}
+
+		// The function returns to the host with an empty call stack, i.e. the same as the top-level code, so the
+		// return value is still on top of its locals.
+		let value = if self.stack.len() > frame {
+			self.stack.top().unbox()
+		} else {
+			Value::Nil
+		};
+		while self.stack.len() > frame {
+			self.stack.pop();
+		}
+		(self.frame, self.function) = outer;
+		Ok(value)
+	}
+
+	/// Runs a full collection, keeping everything the VM can still reach: the stack, the globals, the closures of
+	/// the current and outer call frames, and the interned string constants.
+	///
+	/// Like [`VirtualMachine::reset_heap`], [`Value`]s the host got out of the VM are dangling afterwards if the VM
+	/// doesn't refer to them anymore, unless they're pinned. If the GC is moving (see
+	/// [`GarbageCollector::set_moving`]), they're dangling even if the VM does, while the VM forwards its own.
+	pub fn collect_garbage(&mut self) {
+		let roots = self.roots();
+		self.gc.collect(&roots);
+		if self.gc.is_moving() {
+			self.forward_roots();
+		}
+	}
+
+	/// Runs a minor collection, see [`GarbageCollector::collect_nursery`]. The same as
+	/// [`VirtualMachine::collect_garbage`] applies to the [`Value`]s held by the host.
+	pub fn collect_nursery(&mut self) {
+		let roots = self.roots();
+		self.gc.collect_nursery(&roots);
+	}
+
+	/// Updates the references the VM holds after a moving collection, i.e. everything [`VirtualMachine::roots`]
+	/// returns.
+	fn forward_roots(&mut self) {
+		for value in self.stack.iter_mut().chain(&mut self.globals) {
+			self.gc.forward_value(value);
+		}
+		let closures = self
+			.callstack
+			.iter_mut()
+			.map(|frame| &mut frame.closure)
+			.chain([&mut self.closure]);
+		for closure in closures.flatten() {
+			*closure = self.gc.forward(*closure);
+		}
+		for string in self.strings.iter_mut().flatten() {
+			*string = self.gc.forward(*string);
+		}
+	}
+
+	fn roots(&self) -> Vec<Value> {
+		let mut roots: Vec<_> = self.stack.iter().chain(&self.globals).cloned().collect();
+		let closures = self
+			.callstack
+			.iter()
+			.filter_map(|frame| frame.closure)
+			.chain(self.closure);
+		roots.extend(closures.map(Value::Closure));
+		roots.extend(self.strings.iter().flatten().copied().map(Value::String));
+		roots
+	}
+
+	/// Runs a full collection if the heap has grown large enough, or a minor one if the nursery has, see
+	/// [`GarbageCollector::should_collect`] and [`GarbageCollector::should_collect_nursery`]. An incremental
+	/// collection is advanced by a slice instead, and minor collections wait until it's finished.
+	///
+	/// This is checked by instructions before they allocate, so every value they still need must be kept on the
+	/// stack (or elsewhere reachable) until the allocation is done. It's checked at safepoints as well, see
+	/// [`VirtualMachine::safepoint`].
+	#[inline]
+	fn collect_if_due(&mut self) {
+		if self.gc.is_marking() {
+			if self.gc.mark_slice() {
+				let roots = self.roots();
+				self.gc.finish_collection(&roots);
+			}
+		} else if self.gc.should_collect() {
+			if self.gc.is_incremental() {
+				let roots = self.roots();
+				self.gc.start_collection(&roots);
+			} else {
+				self.collect_garbage();
+			}
+		} else if self.gc.should_collect_nursery() {
+			self.collect_nursery();
+		}
+	}
+
+	/// Allocates a value for an instruction, collecting garbage first if it's due (see
+	/// [`VirtualMachine::collect_if_due`]). If the value doesn't fit in the heap limit, a full collection is forced,
+	/// and the VM runs out of memory if it still doesn't fit.
+	#[inline]
+	fn allocate<T: AllowedAllocationType>(
+		&mut self,
+		value: T,
+	) -> Result<Reference<T>, RuntimeErrorKind>
+	where
+		GarbageCollector: Allocate<T>,
+	{
+		self.collect_if_due();
+		if !self.gc.fits(&value) {
+			self.collect_garbage();
+		}
+		Ok(self.gc.try_allocate(value)?)
+	}
+
+	/// Returns a handle which can interrupt the VM while it's executing bytecode.
+	pub fn interrupt_handle(&self) -> InterruptHandle {
+		self.interrupt.clone()
+	}
+
+	/// Polls the [`InterruptHandle`] at a safepoint, i.e. a backward jump or a call: stops the execution if an
+	/// interrupt is requested, and collects garbage if a collection is requested or due. The requests are consumed, so
+	/// the VM can run again.
+	///
+	/// Checking collections here as well as on allocation lets a loop which never allocates still free the garbage.
+	/// Everything the VM needs must be reachable, the same as [`VirtualMachine::collect_if_due`].
+	#[inline]
+	fn safepoint(&mut self) -> Result<(), RuntimeErrorKind> {
+		if self.interrupt.0.interrupt.swap(false, Ordering::Relaxed) {
+			return Err(RuntimeErrorKind::Interrupted);
+		}
+		if self.interrupt.0.collection.swap(false, Ordering::Relaxed) {
+			self.collect_garbage();
+		} else {
+			self.collect_if_due();
+		}
+		Ok(())
+	}
+
+	/// Limits the length in bytes of any single string the VM creates, so that e.g. an untrusted script can't
+	/// allocate a huge string by repeated concatenation. Exceeding it is a [`RuntimeErrorKind::StringTooLong`] error.
+	/// It's unlimited by default, and kept across [`VirtualMachine::reset`].
+	pub fn set_max_string_length(&mut self, limit: usize) {
+		self.max_string_length = limit;
+	}
+
+	/// Limits the depth of nested function calls, [`DEFAULT_MAX_CALL_DEPTH`] by default. Exceeding it is a
+	/// [`RuntimeErrorKind::CallStackOverflow`] error. It's kept across [`VirtualMachine::reset`].
+	///
+	/// The call stack is preallocated up to the limit, so that calls never reallocate it. If the limit is too large to
+	/// preallocate, the call stack grows on demand instead.
+	pub fn set_max_call_depth(&mut self, limit: usize) {
+		self.max_call_depth = limit;
+		let additional = limit.saturating_sub(self.callstack.len());
+		let _ = self.callstack.try_reserve_exact(additional);
+	}
+
+	/// Checks a string of `length` bytes against the limit before allocating it.
+	#[inline]
+	fn check_string_length(&self, length: usize) -> Result<(), RuntimeErrorKind> {
+		if length > self.max_string_length {
+			return Err(RuntimeErrorKind::StringTooLong {
+				length,
+				limit: self.max_string_length,
+			});
+		}
+		Ok(())
+	}
+
+	/// Sets the program whose entry points name the spans of the calls, see [`VirtualMachine::enter_span`].
+	#[inline]
+	#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+	fn name_spans(&mut self, program: Option<&Rc<Program>>) {
+		#[cfg(feature = "tracing")]
+		{
+			self.spans.program = program.cloned();
+		}
+	}
+
+	/// Opens a span for a call into the function at `entry`, with the `tracing` feature. It's named after the entry
+	/// point of the function, if the program is loaded with one (see [`VirtualMachine::load_with_entries`]).
+	#[inline]
+	#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+	fn enter_span(&mut self, entry: CallPosition) {
+		#[cfg(feature = "tracing")]
+		{
+			// Entry points are few, so they're searched rather than indexed by position.
+			let name = self.spans.program.as_ref().and_then(|program| {
+				program
+					.entries
+					.iter()
+					.find(|(_, position)| **position == entry)
+					.map(|(name, _)| name.clone())
+			});
+			let span = tracing::trace_span!("call", entry, function = name).entered();
+			self.spans.open.push(span);
+		}
+	}
+
+	/// Closes the span of the innermost call, see [`VirtualMachine::enter_span`].
+	#[inline]
+	fn exit_span(&mut self) {
+		#[cfg(feature = "tracing")]
+		self.spans.open.pop();
+	}
+
+	/// Closes the spans of every call, innermost first, once the execution ends either way.
+	fn exit_spans(&mut self) {
+		#[cfg(feature = "tracing")]
+		while self.spans.open.pop().is_some() {}
+	}
+
+	/// Returns the stack index of a local variable in the current call frame.
+	///
+	/// The addition is done in `usize`, since `frame + offset` can be larger than [`LocalOffset::MAX`], and an index
+	/// beyond the stack top is an error instead of silently wrapping to another slot.
+	#[inline]
+	fn local(&self, offset: LocalOffset) -> Result<usize, RuntimeErrorKind> {
+		let slot = self.frame + offset as usize;
+		if slot >= self.stack.len() {
+			return Err(RuntimeErrorKind::InvalidLocal(offset));
+		}
+		Ok(slot)
+	}
+
+	#[inline]
+	fn push(&mut self, value: Value) -> Result<(), RuntimeErrorKind> {
+		if self.stack.len() >= LOCALS_CAPACITY {
+			return Err(RuntimeErrorKind::StackOverflow);
+		}
+		self.stack.push(value);
+		Ok(())
+	}
+
+	#[inline]
+	fn pop(&mut self) -> Result<Value, RuntimeErrorKind> {
+		if self.stack.is_empty() {
+			return Err(RuntimeErrorKind::StackUnderflow);
+		}
+		Ok(self.stack.pop())
+	}
+
+	/// Returns the top `n`-th element of the stack, see [`Stack::peek`].
+	#[inline]
+	fn peek(&self, n: usize) -> Result<&Value, RuntimeErrorKind> {
+		if self.stack.len() <= n {
+			return Err(RuntimeErrorKind::StackUnderflow);
+		}
+		Ok(self.stack.peek(n))
+	}
+
+	/// Returns the start of a new call frame, which takes the top `frame_offset` elements of the stack.
+	#[inline]
+	fn frame_base(&self, frame_offset: LocalOffset) -> Result<usize, RuntimeErrorKind> {
+		self.stack
+			.len()
+			.checked_sub(frame_offset as usize)
+			.ok_or(RuntimeErrorKind::StackUnderflow)
+	}
+
+	/// Saves the call frame of the outer function before calling into another one.
+	#[inline]
+	fn push_frame(&mut self, frame: CallFrame) -> Result<(), RuntimeErrorKind> {
+		if self.callstack.len() >= self.max_call_depth {
+			return Err(RuntimeErrorKind::CallStackOverflow {
+				limit: self.max_call_depth,
+			});
+		}
+		self.callstack.push(frame);
+		Ok(())
 	}
 
 	/// Execute the bytecode.
 	///
 	/// Note that the VM is not reset here, since there may be some needs to execute a piece of bytecode on some
 	/// existing program states.
-	pub fn interpret(&mut self, bytecode: &Bytecode) {
-		let mut reader = BytecodeReader::new(bytecode);
-		macro_rules! arithmetic {
-			($operator: tt as $variant: ident) => {{
-				// SAFETY: Arithmetic operations can only be applied to numbers, so if there's an operand of a
-				// certain reference type, the VM will instantly panic, leaving the GC behavior unimportant.
-				let right = self.stack.pop();
-				let left = self.stack.pop();
-				match (left, right) {
-					(Value::Number(left), Value::Number(right)) => {
-						let result = Value::$variant(left $operator right);
-						self.stack.push(result);
-					}
-					_ => panic!(
-						"arithmetic operator `{}` can only be applied to numbers",
-						stringify!($operator),
-					),
-				}
-			}};
-		}
+	///
+	/// On a [`RuntimeError`], the VM is left as it was when the error occurred, so the host can inspect it. It must
+	/// be [reset](VirtualMachine::reset) before executing anything else, since the call frames of the failed
+	/// execution are still there.
+	pub fn interpret(&mut self, bytecode: &Bytecode) -> Result<(), RuntimeError> {
+		self.interpret_with_endianness::<Endianness>(bytecode)
+	}
+
+	/// Execute the bytecode like [`VirtualMachine::interpret`], reading operands in the byte order [`E`], e.g. to run
+	/// bytecode produced by a big-endian emitter.
+	pub fn interpret_with_endianness<E: ByteOrder>(
+		&mut self,
+		bytecode: &Bytecode,
+	) -> Result<(), RuntimeError> {
+		let mut position = 0;
+		let result = self.execute::<E>(bytecode, &mut position);
+		self.exit_spans();
+		result.map_err(|kind| RuntimeError {
+			kind,
+			position,
+			trace: self.trace(position),
+		})
+	}
+
+	/// Walks the call stack from the innermost call frame, which is executing the instruction at `position`.
+	fn trace(&self, position: usize) -> Vec<TraceFrame> {
+		let innermost = TraceFrame {
+			function: self.function.map(|entry| entry as usize),
+			position,
+		};
+		let outer = self.callstack.iter().rev().map(|frame| TraceFrame {
+			function: frame.function.map(|entry| entry as usize),
+			position: frame.call,
+		});
+		std::iter::once(innermost).chain(outer).collect()
+	}
+
+	/// Executes the bytecode from the instruction at `current`, keeping it at the instruction being executed for
+	/// error reporting.
+	fn execute<E: ByteOrder>(
+		&mut self,
+		bytecode: &Bytecode,
+		current: &mut usize,
+	) -> Result<(), RuntimeErrorKind> {
+		let mut reader = BytecodeReader::<E>::with_endianness(bytecode);
+		reader.seek(*current);
+		self.strings.clear();
+		self.strings.resize(bytecode.constants.len(), None);
 
 		loop {
-			let opcode = reader.fetch();
+			*current = reader.position();
+			#[cfg(feature = "gc-diagnostics")]
+			self.gc.set_allocation_site(Some(*current));
+			let Some(opcode) = reader.fetch_operation() else {
+				return Err(RuntimeErrorKind::InvalidInstruction);
+			};
 			match opcode {
 				OperationCode::Constant => {
 					let index: ConstantIndex = reader.fetch();
-					match reader.load(index as usize) {
-						Constant::Number(n) => self.stack.push(Value::Number(n)),
-						Constant::String(s) => {
-							let allocation = self.gc.allocate(s);
-							self.stack.push(Value::String(allocation));
-						}
-					}
+					handlers::constant(self, &bytecode.constants, index)?;
 				}
-				OperationCode::Nil => self.stack.push(Value::Nil),
-				OperationCode::True => self.stack.push(Value::Boolean(true)),
-				OperationCode::False => self.stack.push(Value::Boolean(false)),
+				OperationCode::Nil => self.push(Value::Nil)?,
+				OperationCode::True => self.push(Value::Boolean(true))?,
+				OperationCode::False => self.push(Value::Boolean(false))?,
 				OperationCode::Fun => {
 					let position: CallPosition = reader.fetch();
 					let arity: LocalOffset = reader.fetch();
-					let fun = self.gc.allocate(FunctionPointer { position, arity });
-					self.stack.push(Value::FunctionPointer(fun));
+					handlers::fun(self, position, arity)?;
 				}
 
-				// SAFETY: Negate operation can only be applied to numbers, so if there's an operand of a certain
-				// reference type, the VM will instantly panic, leaving the GC behavior unimportant.
-				OperationCode::Negate => match self.stack.pop() {
-					Value::Number(n) => self.stack.push(Value::Number(-n)),
-					_ => panic!("negate operator `-` can only be applied to numbers"),
-				},
+				OperationCode::Negate => handlers::negate(self)?,
+				OperationCode::Not => handlers::not(self)?,
 
-				// SAFETY: Logical not operation can be applied to all kinds of types, including the reference types.
-				// However, it does not do dereferencing, so the operand can be GC-ed.
-				OperationCode::Not => {
-					let value = self.stack.pop().as_boolean();
-					self.stack.push(Value::Boolean(!value));
-				}
+				OperationCode::Add => handlers::add(self)?,
+				OperationCode::Subtract => handlers::subtract(self)?,
+				OperationCode::Multiply => handlers::multiply(self)?,
+				OperationCode::Divide => handlers::divide(self)?,
 
-				OperationCode::Add => {
-					// SAFETY: Add operation can be applied to numbers or strings, and the latter is a reference type.
-					// We'll have to keep the reference values on stack before evaluation since we cannot know when
-					// the GC will execute.
-					let right = self.stack.peek(0);
-					let left = self.stack.peek(1);
-					match (left, right) {
-						(Value::Number(left), Value::Number(right)) => {
-							let sum = Value::Number(left + right);
-							self.stack.pop();
-							self.stack.pop();
-							self.stack.push(sum);
-						}
-						(Value::String(left), Value::String(right)) => {
-							let concat = self.gc.allocate(format!("{}{}", **left, **right));
-							self.stack.pop();
-							self.stack.pop();
-							self.stack.push(Value::String(concat));
-						}
-						_ => panic!("add operator `+` can only be applied to numbers or strings"),
-					}
-				}
-				OperationCode::Subtract => arithmetic!(- as Number),
-				OperationCode::Multiply => arithmetic!(* as Number),
-				OperationCode::Divide => arithmetic!(/ as Number),
-
-				OperationCode::Equal => {
-					// SAFETY: Equal operation can be applied to each kind of values, and there's reference types.
-					// Besides, the overloaded [`PartialEq`] operator actually does do dereferencing, so we'll have
-					// to keep the reference values on stack before evaluation since we cannot know when the GC will
-					// execute.
-					let right = self.stack.peek(0);
-					let left = self.stack.peek(1);
-					let equal = Value::Boolean(left == right);
-					self.stack.pop();
-					self.stack.pop();
-					self.stack.push(equal);
-				}
-				OperationCode::Greater => arithmetic!(> as Boolean),
-				OperationCode::Less => arithmetic!(< as Boolean),
-
-				OperationCode::GetGlobal => {
-					let index: GlobalIndex = reader.fetch();
-					let variable = &self.globals[index as usize];
-					let value = if let Value::Upvalue(u) = variable {
-						u.deref().clone()
-					} else {
-						variable.clone()
-					};
-					self.stack.push(value);
-				}
-				OperationCode::SetGlobal => {
-					let index: GlobalIndex = reader.fetch();
-					let value = self.stack.top().clone();
-					let target = &mut self.globals[index as usize];
-					if let Value::Upvalue(u) = target {
-						**u = value;
-					} else {
-						*target = value;
-					}
-				}
+				OperationCode::Equal => handlers::equal(self)?,
+				OperationCode::Greater => handlers::greater(self)?,
+				OperationCode::Less => handlers::less(self)?,
 
-				OperationCode::GetLocal => {
-					let offset: LocalOffset = reader.fetch();
-					let variable = &self.stack[(self.frame + offset) as usize];
-					let value = if let Value::Upvalue(u) = variable {
-						u.deref().clone()
-					} else {
-						variable.clone()
-					};
-					self.stack.push(value);
-				}
-				OperationCode::SetLocal => {
-					let offset: LocalOffset = reader.fetch();
-					let value = self.stack.top().clone();
-					let target = &mut self.stack[(self.frame + offset) as usize];
-					if let Value::Upvalue(u) = target {
-						**u = value
-					} else {
-						*target = value
-					}
-				}
+				OperationCode::GetGlobal => handlers::get_global(self, reader.fetch())?,
+				OperationCode::SetGlobal => handlers::set_global(self, reader.fetch())?,
 
-				// No SAFETY here because the Pop operation means to pop a value out of stack directly.
-				OperationCode::Pop => drop(self.stack.pop()),
+				OperationCode::GetLocal => handlers::get_local(self, reader.fetch())?,
+				OperationCode::SetLocal => handlers::set_local(self, reader.fetch())?,
+
+				OperationCode::Pop => handlers::pop(self)?,
 
 				OperationCode::Closure => {
 					let position: CallPosition = reader.fetch();
 					let arity: LocalOffset = reader.fetch();
-					let closure = self.gc.allocate(Closure {
-						position,
-						arity,
-						upvalues: Vec::new(),
-					});
-					self.stack.push(Value::Closure(closure));
-				}
-				OperationCode::Capture => {
-					let offset: LocalOffset = reader.fetch();
-					let value = self.stack[(self.frame + offset) as usize].clone();
-					let mut closure = match self.stack.top() {
-						Value::Closure(closure) => *closure,
-						_ => panic!("trying to capture value without closure at the stack top"),
-					};
-
-					// The only place that creates an upvalue. There will never be a second-order upvalue.
-					if let Value::Upvalue(upvalue) = value {
-						closure.upvalues.push(upvalue);
-					} else {
-						let upvalue = self.gc.allocate(value);
-						self.stack[(self.frame + offset) as usize] = Value::Upvalue(upvalue);
-						closure.upvalues.push(upvalue);
-					}
-				}
-				OperationCode::GetUpvalue => {
-					let offset: LocalOffset = reader.fetch();
-					let closure = match self.closure {
-						Some(closure) => closure,
-						None => panic!("trying to get upvalue outside a closure"),
-					};
-					let value = closure.upvalues[offset as usize].deref().clone();
-					self.stack.push(value);
-				}
-				OperationCode::SetUpvalue => {
-					let offset: LocalOffset = reader.fetch();
-					let closure = match self.closure {
-						Some(closure) => closure,
-						None => panic!("trying to set upvalue outside a closure"),
-					};
-					let mut upvalue = closure.upvalues[offset as usize];
-					let value = self.stack.top().clone();
-					*upvalue = value;
+					handlers::closure(self, position, arity)?;
 				}
+				OperationCode::Capture => handlers::capture(self, reader.fetch())?,
+				OperationCode::GetUpvalue => handlers::get_upvalue(self, reader.fetch())?,
+				OperationCode::SetUpvalue => handlers::set_upvalue(self, reader.fetch())?,
 
 				OperationCode::JumpIfFalse => {
 					let offset: JumpOffset = reader.fetch();
-					let condition: bool = self.stack.top().as_boolean();
-					if condition == false {
-						reader.jump(offset as isize);
+					let target = handlers::jump_if_false(self, reader.position(), offset)?;
+					if let Some(target) = target {
+						reader.seek(target);
 					}
 				}
 				OperationCode::Jump => {
 					let offset: JumpOffset = reader.fetch();
-					reader.jump(offset as isize);
+					let target = handlers::jump(self, reader.position(), offset)?;
+					reader.seek(target);
 				}
 				OperationCode::Call => {
 					let position: CallPosition = reader.fetch();
 					let frame_offset: LocalOffset = reader.fetch();
-					let last_frame = CallFrame {
-						position: reader.position() as CallPosition,
-						frame: self.frame,
-						closure: mem::replace(&mut self.closure, None),
-					};
-					self.callstack.push(last_frame);
-					self.frame = self.stack.len() as LocalOffset - frame_offset;
+					handlers::call(self, position, frame_offset, reader.position(), *current)?;
 					reader.seek(position as usize);
 				}
-				OperationCode::Invoke => match self.stack.top() {
-					Value::FunctionPointer(f) => {
-						// SAFETY: We get the important part of the function pointer out first, and pops it out of
-						// the stack. It can be GC-ed since we have already known where to call.
-						let position = f.position;
-						let frame_offset = f.arity;
-						self.stack.pop();
-
-						let last_frame = CallFrame {
-							position: reader.position() as CallPosition,
-							frame: self.frame,
-							closure: mem::replace(&mut self.closure, None),
-						};
-						self.callstack.push(last_frame);
-						self.frame = self.stack.len() as LocalOffset - frame_offset;
-						reader.seek(position as usize);
-					}
-					Value::Closure(c) => {
-						let position = c.position;
-						let frame_offset = c.arity;
-
-						let last_frame = CallFrame {
-							position: reader.position() as CallPosition,
-							frame: self.frame,
-							closure: mem::replace(&mut self.closure, None),
-						};
-						self.closure = Some(*c);
-						self.stack.pop(); // We need to put the closure inside callstack before we pop it from the
-						// stack.
-
-						self.callstack.push(last_frame);
-						self.frame = self.stack.len() as LocalOffset - frame_offset;
-						reader.seek(position as usize);
-					}
-					_ => panic!("object is not callable"),
-				},
-				OperationCode::Return => {
-					if let Some(last_frame) = self.callstack.pop() {
-						// SAFETY: We don't actually pop the top element out of stack, which may cause GC bugs. We
-						// just clone it and put it onto the position of the return value, and clears all the other
-						// locals.
-						self.stack[self.frame as usize] = self.stack.top().clone();
-						while self.stack.len() > (self.frame + 1) as usize {
-							self.stack.pop();
-						}
-						self.frame = last_frame.frame;
-						self.closure = last_frame.closure;
-						reader.seek(last_frame.position as usize);
-					} else {
-						break;
-					}
+				OperationCode::Invoke => {
+					let entry = handlers::invoke(self, reader.position(), *current)?;
+					reader.seek(entry);
 				}
+				OperationCode::Return => match handlers::r#return(self)? {
+					Some(position) => reader.seek(position),
+					None => break,
+				},
 
-				OperationCode::Print => {
-					// SAFETY: Print can be applied on reference types, and thus we must keep them on stack before
-					// printing to prevent GC to collect them.
-					println!("{}", self.stack.top());
-					self.stack.pop();
-				}
+				OperationCode::Print => handlers::print(self)?,
+				OperationCode::PrintErr => handlers::print_err(self)?,
 
 				OperationCode::Impossible => unreachable!(),
 			}
 		}
+		Ok(())
 	}
 }
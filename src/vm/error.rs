@@ -0,0 +1,151 @@
+use std::{
+	error::Error,
+	fmt::{Display, Formatter},
+};
+
+use crate::{
+	bytecode::{ConstantIndex, LocalOffset},
+	gc::HeapExhausted,
+};
+
+/// An error aborting the execution of bytecode, returned by
+/// [`VirtualMachine::interpret`](crate::vm::VirtualMachine::interpret).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeError {
+	pub kind: RuntimeErrorKind,
+	/// The position of the instruction which failed.
+	pub position: usize,
+	/// The call frames active when the error occurred, from the innermost (where the instruction failed) to the
+	/// outermost (the top-level code).
+	pub trace: Vec<TraceFrame>,
+}
+
+/// A call frame in the stack trace of a [`RuntimeError`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceFrame {
+	/// The entry position of the function, or [`None`] for the top-level code.
+	pub function: Option<usize>,
+	/// The position of the instruction being executed in this frame, i.e. the failed instruction in the innermost
+	/// frame, or the call into the next inner frame otherwise.
+	pub position: usize,
+}
+
+/// The kinds of [`RuntimeError`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuntimeErrorKind {
+	/// An operator is applied to operands of unsupported types, e.g. adding a number to a string.
+	TypeMismatch {
+		operator: &'static str,
+		expected: &'static str,
+	},
+	/// `Invoke` is applied to a value which is neither a function pointer nor a closure.
+	NotCallable,
+	/// `Capture` is executed without a closure at the stack top.
+	CaptureWithoutClosure,
+	/// An upvalue is accessed outside a closure.
+	UpvalueOutsideClosure,
+	/// An upvalue is accessed which the current closure never captured.
+	InvalidUpvalue(LocalOffset),
+	/// A local variable is accessed beyond the stack top.
+	InvalidLocal(LocalOffset),
+	/// A constant is loaded which the bytecode doesn't have.
+	InvalidConstant(ConstantIndex),
+	/// There's no valid instruction to execute, i.e. an invalid operation code, truncated operands, or the end of the
+	/// code (which is only left by `Return`).
+	InvalidInstruction,
+	/// A jump lands before the beginning of the code.
+	InvalidJump,
+	StackOverflow,
+	StackUnderflow,
+	/// The calls are nested deeper than the limit set by
+	/// [`VirtualMachine::set_max_call_depth`](crate::vm::VirtualMachine::set_max_call_depth), usually an infinite
+	/// recursion.
+	CallStackOverflow {
+		limit: usize,
+	},
+	/// A string would exceed the limit set by
+	/// [`VirtualMachine::set_max_string_length`](crate::vm::VirtualMachine::set_max_string_length).
+	StringTooLong {
+		length: usize,
+		limit: usize,
+	},
+	/// An allocation would exceed the heap limit even after a full collection, see
+	/// [`GarbageCollector::set_max_heap_size`](crate::gc::GarbageCollector::set_max_heap_size).
+	OutOfMemory(HeapExhausted),
+	/// No function of the program is called by the name passed to
+	/// [`VirtualMachine::call`](crate::vm::VirtualMachine::call).
+	UnknownEntry(String),
+	/// The execution is stopped through an [`InterruptHandle`](crate::vm::InterruptHandle).
+	Interrupted,
+}
+
+impl Display for RuntimeErrorKind {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		match self {
+			RuntimeErrorKind::TypeMismatch { operator, expected } => write!(
+				f,
+				"operator `{}` can only be applied to {}",
+				operator, expected
+			),
+			RuntimeErrorKind::NotCallable => write!(f, "object is not callable"),
+			RuntimeErrorKind::CaptureWithoutClosure => write!(
+				f,
+				"trying to capture value without closure at the stack top"
+			),
+			RuntimeErrorKind::UpvalueOutsideClosure => {
+				write!(f, "trying to access upvalue outside a closure")
+			}
+			RuntimeErrorKind::InvalidUpvalue(offset) => {
+				write!(f, "upvalue {} is not captured by the closure", offset)
+			}
+			RuntimeErrorKind::InvalidLocal(offset) => {
+				write!(f, "local variable {} is beyond the stack top", offset)
+			}
+			RuntimeErrorKind::InvalidConstant(index) => {
+				write!(f, "constant index {} out of bounds", index)
+			}
+			RuntimeErrorKind::InvalidInstruction => write!(f, "invalid instruction"),
+			RuntimeErrorKind::InvalidJump => write!(f, "jumping before the beginning of the code"),
+			RuntimeErrorKind::StackOverflow => write!(f, "stack overflow"),
+			RuntimeErrorKind::StackUnderflow => write!(f, "stack underflow"),
+			RuntimeErrorKind::CallStackOverflow { limit } => {
+				write!(f, "call stack overflow, exceeding the max depth {}", limit)
+			}
+			RuntimeErrorKind::StringTooLong { length, limit } => write!(
+				f,
+				"string of {} bytes exceeds the length limit of {} bytes",
+				length, limit
+			),
+			RuntimeErrorKind::OutOfMemory(error) => write!(f, "out of memory, {}", error),
+			RuntimeErrorKind::UnknownEntry(name) => write!(f, "no entry point named `{}`", name),
+			RuntimeErrorKind::Interrupted => write!(f, "interrupted"),
+		}
+	}
+}
+
+impl From<HeapExhausted> for RuntimeErrorKind {
+	fn from(error: HeapExhausted) -> Self {
+		RuntimeErrorKind::OutOfMemory(error)
+	}
+}
+
+impl Display for RuntimeError {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{} at {:#06X}", self.kind, self.position)?;
+		for frame in &self.trace {
+			write!(f, "\n\t{}", frame)?;
+		}
+		Ok(())
+	}
+}
+
+impl Display for TraceFrame {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		match self.function {
+			Some(entry) => write!(f, "at {:#06X} in function {:#06X}", self.position, entry),
+			None => write!(f, "at {:#06X} in script", self.position),
+		}
+	}
+}
+
+impl Error for RuntimeError {}
@@ -0,0 +1,417 @@
+//! The handlers of the operation codes, one per instruction.
+//!
+//! [`VirtualMachine::execute`] only fetches the operation codes and their operands, and moves the reader when the
+//! control flow changes. Everything else an instruction does happens here, so that each instruction can be reasoned
+//! about (and tested) on its own, given the VM state it starts with.
+
+use std::ops::Deref;
+
+use crate::{
+	bytecode::{CallPosition, Constant, ConstantIndex, GlobalIndex, JumpOffset, LocalOffset},
+	gc::{Closure, FunctionPointer},
+	value::Value,
+	vm::{CallFrame, RuntimeErrorKind, VirtualMachine},
+};
+
+#[inline]
+pub(super) fn constant(
+	vm: &mut VirtualMachine,
+	constants: &[Constant],
+	index: ConstantIndex,
+) -> Result<(), RuntimeErrorKind> {
+	if let Some(Some(string)) = vm.strings.get(index as usize) {
+		return vm.push(Value::String(*string));
+	}
+	match constants.get(index as usize) {
+		Some(Constant::Number(n)) => vm.push(Value::Number(*n)),
+		Some(Constant::String(s)) => {
+			vm.check_string_length(s.len())?;
+			vm.collect_if_due();
+			let allocation = match vm.gc.try_intern(s) {
+				Ok(allocation) => allocation,
+				Err(_) => {
+					vm.collect_garbage();
+					vm.gc.try_intern(s)?
+				}
+			};
+			vm.strings[index as usize] = Some(allocation);
+			vm.push(Value::String(allocation))
+		}
+		None => Err(RuntimeErrorKind::InvalidConstant(index)),
+	}
+}
+
+#[inline]
+pub(super) fn fun(
+	vm: &mut VirtualMachine,
+	position: CallPosition,
+	arity: LocalOffset,
+) -> Result<(), RuntimeErrorKind> {
+	let fun = vm.allocate(FunctionPointer { position, arity })?;
+	vm.push(Value::FunctionPointer(fun))
+}
+
+// SAFETY: Negate operation can only be applied to numbers, so if there's an operand of a certain reference type, the
+// VM will instantly abort, leaving the GC behavior unimportant.
+#[inline]
+pub(super) fn negate(vm: &mut VirtualMachine) -> Result<(), RuntimeErrorKind> {
+	match vm.pop()? {
+		Value::Number(n) => vm.push(Value::Number(-n)),
+		_ => Err(RuntimeErrorKind::TypeMismatch {
+			operator: "-",
+			expected: "numbers",
+		}),
+	}
+}
+
+// SAFETY: Logical not operation can be applied to all kinds of types, including the reference types. However, it does
+// not do dereferencing, so the operand can be GC-ed.
+#[inline]
+pub(super) fn not(vm: &mut VirtualMachine) -> Result<(), RuntimeErrorKind> {
+	let value = vm.pop()?.as_boolean();
+	vm.push(Value::Boolean(!value))
+}
+
+#[inline]
+pub(super) fn add(vm: &mut VirtualMachine) -> Result<(), RuntimeErrorKind> {
+	// SAFETY: Add operation can be applied to numbers or strings, and the latter is a reference type. We'll have to
+	// keep the reference values on stack before evaluation since we cannot know when the GC will execute.
+	let right = vm.peek(0)?;
+	let left = vm.peek(1)?;
+	let sum = match (left, right) {
+		(Value::Number(left), Value::Number(right)) => Value::Number(left + right),
+		(Value::String(left), Value::String(right)) => {
+			vm.check_string_length(left.len() + right.len())?;
+			let concat = format!("{}{}", **left, **right);
+			Value::String(vm.allocate(concat)?)
+		}
+		_ => {
+			return Err(RuntimeErrorKind::TypeMismatch {
+				operator: "+",
+				expected: "numbers or strings",
+			})
+		}
+	};
+	vm.stack.pop();
+	vm.stack.pop();
+	vm.stack.push(sum);
+	Ok(())
+}
+
+/// Applies a binary operator on numbers, i.e. the arithmetic and comparison instructions other than
+/// [`OperationCode::Add`](crate::bytecode::OperationCode::Add).
+///
+/// The operator is a generic parameter, so it's inlined into the handler of every instruction.
+//
+// SAFETY: Arithmetic operations can only be applied to numbers, so if there's an operand of a certain reference type,
+// the VM will instantly abort, leaving the GC behavior unimportant.
+#[inline]
+fn arithmetic(
+	vm: &mut VirtualMachine,
+	operator: &'static str,
+	apply: impl FnOnce(f64, f64) -> Value,
+) -> Result<(), RuntimeErrorKind> {
+	let right = vm.pop()?;
+	let left = vm.pop()?;
+	match (left, right) {
+		(Value::Number(left), Value::Number(right)) => vm.push(apply(left, right)),
+		_ => Err(RuntimeErrorKind::TypeMismatch {
+			operator,
+			expected: "numbers",
+		}),
+	}
+}
+
+#[inline]
+pub(super) fn subtract(vm: &mut VirtualMachine) -> Result<(), RuntimeErrorKind> {
+	arithmetic(vm, "-", |left, right| Value::Number(left - right))
+}
+
+#[inline]
+pub(super) fn multiply(vm: &mut VirtualMachine) -> Result<(), RuntimeErrorKind> {
+	arithmetic(vm, "*", |left, right| Value::Number(left * right))
+}
+
+#[inline]
+pub(super) fn divide(vm: &mut VirtualMachine) -> Result<(), RuntimeErrorKind> {
+	arithmetic(vm, "/", |left, right| Value::Number(left / right))
+}
+
+#[inline]
+pub(super) fn greater(vm: &mut VirtualMachine) -> Result<(), RuntimeErrorKind> {
+	arithmetic(vm, ">", |left, right| Value::Boolean(left > right))
+}
+
+#[inline]
+pub(super) fn less(vm: &mut VirtualMachine) -> Result<(), RuntimeErrorKind> {
+	arithmetic(vm, "<", |left, right| Value::Boolean(left < right))
+}
+
+#[inline]
+pub(super) fn equal(vm: &mut VirtualMachine) -> Result<(), RuntimeErrorKind> {
+	// SAFETY: Equal operation can be applied to each kind of values, and there's reference types. Besides, the
+	// overloaded [`PartialEq`] operator actually does do dereferencing, so we'll have to keep the reference values on
+	// stack before evaluation since we cannot know when the GC will execute.
+	let right = vm.peek(0)?;
+	let left = vm.peek(1)?;
+	let equal = Value::Boolean(left == right);
+	vm.stack.pop();
+	vm.stack.pop();
+	vm.stack.push(equal);
+	Ok(())
+}
+
+#[inline]
+pub(super) fn get_global(
+	vm: &mut VirtualMachine,
+	index: GlobalIndex,
+) -> Result<(), RuntimeErrorKind> {
+	let value = vm.globals[index as usize].unbox();
+	vm.push(value)
+}
+
+#[inline]
+pub(super) fn set_global(
+	vm: &mut VirtualMachine,
+	index: GlobalIndex,
+) -> Result<(), RuntimeErrorKind> {
+	let value = vm.peek(0)?.clone();
+	vm.set_global(index, value);
+	Ok(())
+}
+
+#[inline]
+pub(super) fn get_local(
+	vm: &mut VirtualMachine,
+	offset: LocalOffset,
+) -> Result<(), RuntimeErrorKind> {
+	let slot = vm.local(offset)?;
+	let value = vm.stack[slot].unbox();
+	vm.push(value)
+}
+
+#[inline]
+pub(super) fn set_local(
+	vm: &mut VirtualMachine,
+	offset: LocalOffset,
+) -> Result<(), RuntimeErrorKind> {
+	let slot = vm.local(offset)?;
+	// The stack top may be a captured local itself, whose upvalue must never be boxed into another upvalue (or
+	// itself), so values are always stored unboxed.
+	let value = vm.peek(0)?.unbox();
+	let target = &mut vm.stack[slot];
+	if let Value::Upvalue(u) = target {
+		**u = value;
+		vm.gc.remember(*u);
+	} else {
+		*target = value
+	}
+	Ok(())
+}
+
+// No SAFETY here because the Pop operation means to pop a value out of stack directly.
+#[inline]
+pub(super) fn pop(vm: &mut VirtualMachine) -> Result<(), RuntimeErrorKind> {
+	vm.pop()?;
+	Ok(())
+}
+
+#[inline]
+pub(super) fn closure(
+	vm: &mut VirtualMachine,
+	position: CallPosition,
+	arity: LocalOffset,
+) -> Result<(), RuntimeErrorKind> {
+	let closure = vm.allocate(Closure {
+		position,
+		arity,
+		upvalues: Vec::new(),
+	})?;
+	vm.push(Value::Closure(closure))
+}
+
+#[inline]
+pub(super) fn capture(
+	vm: &mut VirtualMachine,
+	offset: LocalOffset,
+) -> Result<(), RuntimeErrorKind> {
+	let slot = vm.local(offset)?;
+	if !matches!(vm.peek(0)?, Value::Closure(_)) {
+		return Err(RuntimeErrorKind::CaptureWithoutClosure);
+	}
+
+	// The only place that creates an upvalue. There will never be a second-order upvalue.
+	let upvalue = match vm.stack[slot] {
+		Value::Upvalue(upvalue) => upvalue,
+		_ => {
+			// SAFETY: The local is boxed after allocating, since a moving collection may relocate it (and the
+			// closure) in the meantime. The upvalue is remembered, in case it's marked by an incremental collection
+			// before the local is stored into it.
+			let mut upvalue = vm.allocate(Value::Nil)?;
+			*upvalue = vm.stack[slot].clone();
+			vm.gc.remember(upvalue);
+			upvalue
+		}
+	};
+	// The closure may be the captured local itself, so it's read before the local is boxed.
+	let mut closure = match vm.peek(0)? {
+		Value::Closure(closure) => *closure,
+		_ => return Err(RuntimeErrorKind::CaptureWithoutClosure),
+	};
+	vm.stack[slot] = Value::Upvalue(upvalue);
+	closure.upvalues.push(upvalue);
+	// A collection may have happened since the closure is created.
+	vm.gc.remember(closure);
+	Ok(())
+}
+
+#[inline]
+pub(super) fn get_upvalue(
+	vm: &mut VirtualMachine,
+	offset: LocalOffset,
+) -> Result<(), RuntimeErrorKind> {
+	let closure = vm.closure.ok_or(RuntimeErrorKind::UpvalueOutsideClosure)?;
+	let value = match closure.upvalues.get(offset as usize) {
+		Some(upvalue) => upvalue.deref().clone(),
+		None => return Err(RuntimeErrorKind::InvalidUpvalue(offset)),
+	};
+	vm.push(value)
+}
+
+#[inline]
+pub(super) fn set_upvalue(
+	vm: &mut VirtualMachine,
+	offset: LocalOffset,
+) -> Result<(), RuntimeErrorKind> {
+	let closure = vm.closure.ok_or(RuntimeErrorKind::UpvalueOutsideClosure)?;
+	let mut upvalue = match closure.upvalues.get(offset as usize) {
+		Some(upvalue) => *upvalue,
+		None => return Err(RuntimeErrorKind::InvalidUpvalue(offset)),
+	};
+	*upvalue = vm.peek(0)?.unbox();
+	vm.gc.remember(upvalue);
+	Ok(())
+}
+
+/// Returns the jump target if the condition on the stack top is false, where `next` is the position after the jump
+/// instruction.
+#[inline]
+pub(super) fn jump_if_false(
+	vm: &mut VirtualMachine,
+	next: usize,
+	offset: JumpOffset,
+) -> Result<Option<usize>, RuntimeErrorKind> {
+	let condition: bool = vm.peek(0)?.as_boolean();
+	if condition {
+		return Ok(None);
+	}
+	jump(vm, next, offset).map(Some)
+}
+
+/// Returns the jump target, where `next` is the position after the jump instruction.
+#[inline]
+pub(super) fn jump(
+	vm: &mut VirtualMachine,
+	next: usize,
+	offset: JumpOffset,
+) -> Result<usize, RuntimeErrorKind> {
+	if offset < 0 {
+		vm.safepoint()?;
+	}
+	next.checked_add_signed(offset as isize)
+		.ok_or(RuntimeErrorKind::InvalidJump)
+}
+
+/// Enters the function at `position`, where `next` is the position to return to, and `call` is the position of the
+/// calling instruction.
+#[inline]
+pub(super) fn call(
+	vm: &mut VirtualMachine,
+	position: CallPosition,
+	frame_offset: LocalOffset,
+	next: usize,
+	call: usize,
+) -> Result<(), RuntimeErrorKind> {
+	vm.safepoint()?;
+	let frame = vm.frame_base(frame_offset)?;
+	let last_frame = CallFrame {
+		position: next as CallPosition,
+		frame: vm.frame,
+		closure: vm.closure.take(),
+		function: vm.function.replace(position),
+		call,
+	};
+	vm.push_frame(last_frame)?;
+	vm.enter_span(position);
+	vm.frame = frame;
+	Ok(())
+}
+
+/// Enters the function or closure on the stack top and returns its entry, see [`call`] for `next` and `call`.
+#[inline]
+pub(super) fn invoke(
+	vm: &mut VirtualMachine,
+	next: usize,
+	call: usize,
+) -> Result<usize, RuntimeErrorKind> {
+	vm.safepoint()?;
+	let (position, frame_offset, closure) = match vm.peek(0)? {
+		Value::FunctionPointer(f) => (f.position, f.arity, None),
+		Value::Closure(c) => (c.position, c.arity, Some(*c)),
+		_ => return Err(RuntimeErrorKind::NotCallable),
+	};
+	// SAFETY: We get the important part of the callee out first, and pops it out of the stack. A function pointer can
+	// be GC-ed since we have already known where to call, and a closure is kept as the current closure.
+	vm.stack.pop();
+	let frame = vm.frame_base(frame_offset)?;
+	let last_frame = CallFrame {
+		position: next as CallPosition,
+		frame: vm.frame,
+		closure: vm.closure.take(),
+		function: vm.function.replace(position),
+		call,
+	};
+	vm.push_frame(last_frame)?;
+	vm.enter_span(position);
+	vm.closure = closure;
+	vm.frame = frame;
+	Ok(position as usize)
+}
+
+/// Leaves the current function and returns the position to return to, or [`None`] if the top-level code returns.
+#[inline]
+pub(super) fn r#return(vm: &mut VirtualMachine) -> Result<Option<usize>, RuntimeErrorKind> {
+	if !vm.callstack.is_empty() && vm.stack.len() <= vm.frame {
+		return Err(RuntimeErrorKind::StackUnderflow);
+	}
+	let Some(last_frame) = vm.callstack.pop() else {
+		return Ok(None);
+	};
+	vm.exit_span();
+	// SAFETY: We don't actually pop the top element out of stack, which may cause GC bugs. We just clone it and put it
+	// onto the position of the return value, and clears all the other locals.
+	vm.stack[vm.frame] = vm.stack.top().unbox();
+	while vm.stack.len() > vm.frame + 1 {
+		vm.stack.pop();
+	}
+	vm.frame = last_frame.frame;
+	vm.closure = last_frame.closure;
+	vm.function = last_frame.function;
+	Ok(Some(last_frame.position as usize))
+}
+
+#[inline]
+pub(super) fn print(vm: &mut VirtualMachine) -> Result<(), RuntimeErrorKind> {
+	// SAFETY: Print can be applied on reference types, and thus we must keep them on stack before printing to prevent
+	// GC to collect them.
+	println!("{}", vm.peek(0)?);
+	vm.stack.pop();
+	Ok(())
+}
+
+#[inline]
+pub(super) fn print_err(vm: &mut VirtualMachine) -> Result<(), RuntimeErrorKind> {
+	eprintln!("{}", vm.peek(0)?);
+	vm.stack.pop();
+	Ok(())
+}
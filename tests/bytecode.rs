@@ -0,0 +1,304 @@
+use byteorder::{BigEndian, LittleEndian};
+use mussel_vm::{
+	bytecode,
+	bytecode::{
+		diff, minimize, stats, Bytecode, BytecodeReader, BytecodeWriter, CallPosition, Constant,
+		ConstantIndex, Emit, Fetch, Hunk, Instruction, JumpOffset, LocalOffset, OperationCode,
+	},
+	value::Value,
+	vm::{RuntimeErrorKind, VirtualMachine},
+};
+
+#[test]
+fn configurable_endianness() {
+	let mut bytecode = Bytecode {
+		code: Vec::new(),
+		constants: Vec::new(),
+	};
+	let mut writer = BytecodeWriter::<BigEndian>::with_endianness(&mut bytecode);
+	writer.emit(OperationCode::Jump);
+	writer.emit(-2 as JumpOffset);
+	writer.emit(0x1234u16);
+	assert_eq!(
+		bytecode.code,
+		[OperationCode::Jump as u8, 0xFF, 0xFE, 0x12, 0x34]
+	);
+
+	let mut reader = BytecodeReader::<BigEndian>::with_endianness(&bytecode);
+	assert!(matches!(reader.fetch(), OperationCode::Jump));
+	assert_eq!(Fetch::<JumpOffset>::fetch(&mut reader), -2);
+	assert_eq!(Fetch::<u16>::fetch(&mut reader), 0x1234);
+
+	let mut reader = BytecodeReader::<LittleEndian>::with_endianness(&bytecode);
+	reader.seek(3);
+	assert_eq!(Fetch::<u16>::fetch(&mut reader), 0x3412);
+}
+
+#[test]
+fn big_endian_bytecode_runs() {
+	let mut bytecode = Bytecode {
+		code: Vec::new(),
+		constants: Vec::new(),
+	};
+	let mut writer = BytecodeWriter::<BigEndian>::with_endianness(&mut bytecode);
+	for i in 0..=0x0102 {
+		writer.define(Constant::Number(i as f64));
+	}
+	let instructions = [
+		Instruction::Jump(3),
+		Instruction::Constant(0x0001),
+		// 06:
+		Instruction::Constant(0x0102),
+		Instruction::SetGlobal(0),
+		Instruction::Return,
+	];
+	for instruction in instructions {
+		writer.emit(instruction);
+	}
+
+	let decoded: Vec<_> = bytecode
+		.instructions_with_endianness::<BigEndian>()
+		.map(|(_, instruction)| instruction)
+		.collect();
+	assert_eq!(decoded, instructions);
+	assert_eq!(
+		bytecode.instruction_at_with_endianness::<BigEndian>(6),
+		Some(Instruction::Constant(0x0102))
+	);
+
+	let mut vm = VirtualMachine::new();
+	vm.interpret_with_endianness::<BigEndian>(&bytecode)
+		.unwrap();
+	assert_eq!(vm.global(0), Value::Number(258.0));
+
+	// Read in the default byte order, the jump lands beyond the end of the code.
+	let mut vm = VirtualMachine::new();
+	assert_eq!(
+		vm.interpret(&bytecode).unwrap_err().kind,
+		RuntimeErrorKind::InvalidInstruction
+	);
+}
+
+#[test]
+fn diff_reports_constants_and_changed_ranges() {
+	let old = bytecode! {
+		const [Constant::Number(1.0), Constant::Number(2.0)]
+
+		OperationCode::Constant; 0 as ConstantIndex;
+		OperationCode::Constant; 1 as ConstantIndex;
+		OperationCode::Add;
+		OperationCode::Print;
+		OperationCode::Return;
+	};
+	let new = bytecode! {
+		const [Constant::Number(1.0), Constant::String("two".into())]
+
+		OperationCode::Constant; 0 as ConstantIndex;
+		OperationCode::Constant; 1 as ConstantIndex;
+		OperationCode::Subtract;
+		OperationCode::Negate;
+		OperationCode::Print;
+		OperationCode::Return;
+	};
+
+	let difference = diff(&old, &new);
+	assert_eq!(
+		difference.constants_added,
+		[(1, Constant::String("two".into()))]
+	);
+	assert_eq!(difference.constants_removed, [(1, Constant::Number(2.0))]);
+	assert_eq!(
+		difference.hunks,
+		[Hunk {
+			old: 6..7,
+			new: 6..8,
+			removed: vec![Instruction::Add],
+			added: vec![Instruction::Subtract, Instruction::Negate],
+		}]
+	);
+	assert_eq!(
+		difference.to_string(),
+		"const:\n-\t01 <2>\n+\t01 <\"two\">\ncode:\n@@ 0x0006..0x0007 => 0x0006..0x0008 @@\n-\t06 \
+		 ADD\n+\t06 SUBTRACT\n+\t07 NEGATE\n"
+	);
+
+	assert!(diff(&old, &old).is_empty());
+	assert_eq!(
+		new.instructions()
+			.map(|(position, _)| position)
+			.collect::<Vec<_>>(),
+		[0, 3, 6, 7, 8, 9]
+	);
+}
+
+#[test]
+fn static_statistics() {
+	let bytecode = bytecode! {
+		const [Constant::Number(1.0), Constant::String("mussel".into())]
+
+		// main:
+		OperationCode::Constant; 0 as ConstantIndex;
+		OperationCode::Call; 12 as CallPosition; 1 as LocalOffset;
+		OperationCode::Print;
+		OperationCode::Return;
+		// 09: unreachable
+		OperationCode::Nil;
+		OperationCode::Nil;
+		OperationCode::Nil;
+		// 12: fun(x) { while (x) { x; "mussel"; } return nil; }
+		OperationCode::GetLocal; 0 as LocalOffset;
+		OperationCode::JumpIfFalse; 11 as JumpOffset;
+		OperationCode::Pop;
+		OperationCode::GetLocal; 0 as LocalOffset;
+		OperationCode::Constant; 1 as ConstantIndex;
+		OperationCode::Pop;
+		OperationCode::Pop;
+		OperationCode::Jump; -16 as JumpOffset;
+		OperationCode::Pop;
+		OperationCode::Nil;
+		OperationCode::Return;
+	};
+
+	let stats = stats(&bytecode);
+	assert_eq!(stats.code_size, bytecode.code.len());
+	assert_eq!(stats.instructions, 18);
+	assert_eq!(stats.opcodes[0], (OperationCode::Nil, 4));
+	assert!(stats.opcodes.contains(&(OperationCode::GetLocal, 2)));
+	assert_eq!(
+		(stats.numbers, stats.strings, stats.string_bytes),
+		(1, 1, 6)
+	);
+	// The frame holds `x`, `x` and "mussel" at most.
+	assert_eq!(stats.max_stack_depth, 3);
+
+	// Trailing garbage is only part of the size.
+	let mut garbage = bytecode.clone();
+	garbage.code.push(0xC8);
+	let garbage = mussel_vm::bytecode::stats(&garbage);
+	assert_eq!(garbage.code_size, bytecode.code.len() + 1);
+	assert_eq!(garbage.instructions, 18);
+}
+
+#[test]
+fn loop_headers() {
+	let bytecode = bytecode! {
+		const []
+
+		// 00: outer loop
+		OperationCode::True;
+		OperationCode::JumpIfFalse; 9 as JumpOffset;
+		// 04: inner loop, jumping back while the condition is false
+		OperationCode::False;
+		OperationCode::JumpIfFalse; -4 as JumpOffset;
+		OperationCode::Pop;
+		OperationCode::Jump; -12 as JumpOffset;
+		// 13:
+		OperationCode::Return;
+	};
+	assert_eq!(bytecode.loop_headers(), [0, 4]);
+
+	let mut garbage = bytecode.clone();
+	garbage.code.push(0xC8);
+	assert_eq!(garbage.loop_headers(), [0, 4]);
+}
+
+#[test]
+fn minimize_a_crashing_program() {
+	let bytecode = bytecode! {
+		const [Constant::Number(1.0), Constant::String("mussel".into())]
+
+		OperationCode::Constant; 0 as ConstantIndex;
+		OperationCode::Print;
+		OperationCode::Constant; 1 as ConstantIndex;
+		OperationCode::Jump; 0 as JumpOffset;
+		OperationCode::Pop;
+		OperationCode::True;
+		OperationCode::Negate;
+		OperationCode::Return;
+	};
+	let crashes_on_negate = |bytecode: &Bytecode| {
+		VirtualMachine::new()
+			.interpret(bytecode)
+			.is_err_and(|error| {
+				matches!(
+					error.kind,
+					RuntimeErrorKind::TypeMismatch { operator: "-", .. }
+				)
+			})
+	};
+
+	let minimal = minimize(&bytecode, crashes_on_negate);
+	let instructions: Vec<_> = minimal.instructions().map(|(_, x)| x).collect();
+	assert_eq!(instructions.len(), 2);
+	assert_eq!(instructions[1], Instruction::Negate);
+	assert!(crashes_on_negate(&minimal));
+	assert!(minimal.constants.len() <= 1);
+}
+
+#[test]
+fn minimize_with_invalid_constants() {
+	let bytecode = bytecode! {
+		const [Constant::Number(1.0), Constant::Number(2.0)]
+
+		OperationCode::Constant; 0 as ConstantIndex;
+		OperationCode::Pop;
+		OperationCode::Constant; 5 as ConstantIndex;
+		OperationCode::Return;
+	};
+	let fails_on_a_constant = |bytecode: &Bytecode| {
+		VirtualMachine::new()
+			.interpret(bytecode)
+			.is_err_and(|error| matches!(error.kind, RuntimeErrorKind::InvalidConstant(_)))
+	};
+
+	// The index stays past the constants as they're dropped.
+	let minimal = minimize(&bytecode, fails_on_a_constant);
+	let instructions: Vec<_> = minimal.instructions().map(|(_, x)| x).collect();
+	assert_eq!(instructions, [Instruction::Constant(3)]);
+	assert!(minimal.constants.is_empty());
+}
+
+#[test]
+fn minimize_with_invalid_targets() {
+	let chunks = [
+		bytecode! {
+			const []
+
+			OperationCode::Nil;
+			OperationCode::Pop;
+			OperationCode::Jump; -100 as JumpOffset;
+			OperationCode::Return;
+		},
+		bytecode! {
+			const [Constant::Number(1.0)]
+
+			OperationCode::Nil;
+			OperationCode::Pop;
+			// Into the operand of the `Constant`.
+			OperationCode::Jump; 1 as JumpOffset;
+			OperationCode::Constant; 0 as ConstantIndex;
+			OperationCode::Return;
+		},
+		bytecode! {
+			const []
+
+			OperationCode::Nil;
+			OperationCode::Pop;
+			OperationCode::Fun; 999 as CallPosition; 0 as LocalOffset;
+			OperationCode::Invoke;
+			OperationCode::Return;
+		},
+	];
+
+	for bytecode in chunks {
+		let error = VirtualMachine::new().interpret(&bytecode).unwrap_err().kind;
+		let fails_the_same = |bytecode: &Bytecode| {
+			VirtualMachine::new()
+				.interpret(bytecode)
+				.is_err_and(|e| e.kind == error)
+		};
+		let minimal = minimize(&bytecode, fails_the_same);
+		assert!(fails_the_same(&minimal));
+		assert!(minimal.code.len() <= bytecode.code.len());
+	}
+}
@@ -0,0 +1,492 @@
+use std::{cell::RefCell, rc::Rc};
+
+use mussel_vm::{
+	gc::{
+		Allocate, AllocationKind, Closure, Downcast, FunctionPointer, GarbageCollector, GcEvent,
+		Reference,
+	},
+	value::Value,
+};
+
+#[test]
+fn pinned_allocations_survive_clear() {
+	let mut gc = GarbageCollector::new();
+	let pinned = {
+		let reference = gc.allocate(String::from("pinned"));
+		gc.pin(reference)
+	};
+	let address = pinned.as_ptr();
+	gc.allocate(String::from("transient"));
+	assert_eq!(gc.pins(&pinned.reference()), 1);
+
+	let copy = pinned.clone();
+	assert_eq!(gc.pins(&pinned.reference()), 2);
+	drop(copy);
+	assert_eq!(gc.pins(&pinned.reference()), 1);
+
+	gc.clear();
+	assert_eq!(&*pinned, "pinned");
+	assert_eq!(pinned.as_ptr(), address);
+
+	// The pinned string is still interned.
+	let again = gc.allocate(String::from("pinned"));
+	assert!(again == pinned.reference());
+}
+
+#[test]
+fn pinned_allocations_outlive_the_gc() {
+	let mut gc = GarbageCollector::new();
+	let upvalue = gc.allocate(Value::Number(1.0));
+	let closure = gc.allocate(Closure {
+		position: 0,
+		arity: 0,
+		upvalues: vec![upvalue],
+	});
+	let pinned = gc.pin(closure);
+
+	// What a pinned allocation refers to is kept as well.
+	gc.clear();
+	assert_eq!(*pinned.upvalues[0], Value::Number(1.0));
+
+	// The handles are still valid, and can be dropped, after the GC is gone.
+	drop(gc);
+	let copy = pinned.clone();
+	drop(pinned);
+	assert_eq!(*copy.upvalues[0], Value::Number(1.0));
+}
+
+#[cfg(feature = "gc-diagnostics")]
+#[test]
+fn allocation_sites() {
+	use mussel_vm::{
+		bytecode,
+		bytecode::{CallPosition, Constant, ConstantIndex, LocalOffset, OperationCode},
+		vm::VirtualMachine,
+	};
+
+	let bytecode = bytecode! {
+		const [Constant::String("a rather long string constant".into())]
+
+		OperationCode::Constant; 0 as ConstantIndex;
+		OperationCode::Closure; 0 as CallPosition; 0 as LocalOffset;
+		OperationCode::Closure; 0 as CallPosition; 0 as LocalOffset;
+		OperationCode::Return;
+	};
+	let mut vm = VirtualMachine::new();
+	vm.interpret(&bytecode).unwrap();
+
+	let sites = vm.gc().allocation_sites();
+	assert_eq!(sites.len(), 3);
+	assert_eq!(sites[0].site, Some(0));
+	assert_eq!(sites[0].count, 1);
+	assert!(sites.iter().all(|site| site.bytes > 0));
+	assert_eq!(vm.gc().sequence(), 3);
+}
+
+#[test]
+#[cfg(feature = "gc-validate")]
+#[should_panic(expected = "use after free of a String allocation")]
+fn use_after_free_is_detected() {
+	let mut gc = GarbageCollector::new();
+	let dangling = gc.allocate(String::from("mussel"));
+	gc.clear();
+	assert_eq!(dangling.as_str(), "mussel");
+}
+
+/// Checks that a reference, once type-erased, downcasts to `T` only, and to the very value it holds.
+fn assert_downcasts_only_to<T>(reference: Reference<T>) -> *const T
+where
+	Reference<()>: Downcast<T>
+		+ Downcast<String>
+		+ Downcast<FunctionPointer>
+		+ Downcast<Closure>
+		+ Downcast<Value>,
+{
+	let mut erased = unsafe { reference.cast::<()>() };
+	let kinds = [
+		Downcast::<String>::downcast(&erased).is_some(),
+		Downcast::<FunctionPointer>::downcast(&erased).is_some(),
+		Downcast::<Closure>::downcast(&erased).is_some(),
+		Downcast::<Value>::downcast(&erased).is_some(),
+	];
+	assert_eq!(kinds.iter().filter(|&&kind| kind).count(), 1);
+	let value: *const T = Downcast::<T>::downcast(&erased).unwrap();
+	let value_mut: *const T = Downcast::<T>::downcast_mut(&mut erased).unwrap();
+	assert_eq!(value, value_mut);
+	assert_eq!(value, reference.as_ptr());
+	value
+}
+
+#[test]
+fn downcast_every_allocation_kind() {
+	let mut gc = GarbageCollector::new();
+	let string = gc.allocate(String::from("mussel"));
+	let fun = gc.allocate(FunctionPointer {
+		position: 0x10,
+		arity: 2,
+	});
+	let closure = gc.allocate(Closure {
+		position: 0x20,
+		arity: 1,
+		upvalues: Vec::new(),
+	});
+	let upvalue = gc.allocate(Value::Number(114514.0));
+
+	unsafe {
+		assert_eq!(*assert_downcasts_only_to::<String>(string), "mussel");
+		let fun = &*assert_downcasts_only_to::<FunctionPointer>(fun);
+		assert_eq!((fun.position, fun.arity), (0x10, 2));
+		let closure = &*assert_downcasts_only_to::<Closure>(closure);
+		assert_eq!((closure.position, closure.arity), (0x20, 1));
+		assert_eq!(
+			*assert_downcasts_only_to::<Value>(upvalue),
+			Value::Number(114514.0)
+		);
+	}
+}
+
+#[test]
+fn iterate_live_objects() {
+	let mut gc = GarbageCollector::new();
+	let string = gc.allocate(String::from("mussel"));
+	gc.allocate(Value::Number(1.0));
+	gc.intern("mussel");
+
+	let objects: Vec<_> = gc.iter_objects().collect();
+	assert_eq!(objects.len(), 2);
+	assert_eq!(objects[0].address, string.address());
+	assert_eq!(objects[0].kind, AllocationKind::String);
+	assert_eq!(objects[0].description, "\"mussel\"");
+	assert_eq!(objects[1].kind, AllocationKind::Upvalue);
+	assert_eq!(objects[1].description, "<upvalue 1>");
+	assert!(objects.iter().all(|object| object.size > 0));
+	assert_eq!(
+		objects[1].to_string(),
+		format!(
+			"{:#x} Upvalue ({} bytes) <upvalue 1>",
+			objects[1].address, objects[1].size
+		)
+	);
+
+	gc.clear();
+	assert_eq!(gc.iter_objects().count(), 0);
+}
+
+#[test]
+fn string_pool_statistics() {
+	let mut gc = GarbageCollector::new();
+	gc.allocate(String::from("mussel"));
+	gc.intern("mussel");
+	gc.intern("mussel");
+	gc.intern("vm");
+
+	let stats = gc.string_pool_stats();
+	assert_eq!((stats.strings, stats.bytes), (2, 8));
+	assert_eq!((stats.hits, stats.misses, stats.bytes_saved), (2, 2, 12));
+
+	gc.clear();
+	let stats = gc.string_pool_stats();
+	assert_eq!((stats.strings, stats.bytes), (0, 0));
+	assert_eq!((stats.hits, stats.misses), (2, 2));
+}
+
+#[test]
+fn collect_unreachable_allocations() {
+	let mut gc = GarbageCollector::new();
+	let fun = gc.allocate(FunctionPointer {
+		position: 0,
+		arity: 0,
+	});
+	let upvalue = gc.allocate(Value::FunctionPointer(fun));
+	let closure = gc.allocate(Closure {
+		position: 0,
+		arity: 0,
+		upvalues: vec![upvalue],
+	});
+	let garbage = gc.allocate(Value::Nil);
+	gc.allocate(Closure {
+		position: 0,
+		arity: 0,
+		upvalues: vec![garbage],
+	});
+	let kept = gc.allocate(String::from("kept"));
+	gc.allocate(String::from("dropped"));
+	assert_eq!(gc.iter_objects().count(), 7);
+
+	// The function is only reachable through the closure and its upvalue.
+	gc.collect(&[Value::Closure(closure), Value::String(kept)]);
+	let addresses: Vec<_> = gc.iter_objects().map(|object| object.address).collect();
+	assert_eq!(addresses.len(), 4);
+	assert!(addresses.contains(&fun.address()));
+	assert!(addresses.contains(&upvalue.address()));
+	assert!(addresses.contains(&closure.address()));
+	assert!(addresses.contains(&kept.address()));
+
+	// The pool doesn't keep interned strings alive, and forgets them once they're collected.
+	assert_eq!(gc.string_pool_stats().strings, 1);
+	assert_eq!(gc.intern("kept"), kept);
+	gc.intern("dropped");
+	assert_eq!(gc.string_pool_stats().misses, 3);
+
+	gc.collect(&[]);
+	assert_eq!(gc.iter_objects().count(), 0);
+	assert_eq!(gc.string_pool_stats().strings, 0);
+}
+
+#[cfg(feature = "gc-stress")]
+#[test]
+fn stress_collects_before_every_allocation() {
+	let mut gc = GarbageCollector::new();
+	assert!(gc.should_collect());
+	gc.collect(&[]);
+	assert!(gc.should_collect());
+}
+
+#[test]
+fn minor_collections_only_free_young_allocations() {
+	let mut gc = GarbageCollector::new();
+	let mut upvalue = gc.allocate(Value::Nil);
+	let unreachable = gc.allocate(Value::Nil);
+	gc.collect(&[Value::Upvalue(upvalue), Value::Upvalue(unreachable)]);
+	assert!(upvalue.is_mature());
+
+	let young = gc.allocate(FunctionPointer {
+		position: 0,
+		arity: 0,
+	});
+	assert!(!young.is_mature());
+	*upvalue = Value::FunctionPointer(young);
+	gc.remember(upvalue);
+	gc.allocate(Value::Nil);
+	assert_eq!(gc.iter_objects().count(), 4);
+
+	// Mature allocations are kept even without roots, and the young function is kept through the remembered
+	// upvalue.
+	gc.collect_nursery(&[]);
+	let addresses: Vec<_> = gc.iter_objects().map(|object| object.address).collect();
+	assert_eq!(
+		addresses,
+		[upvalue.address(), unreachable.address(), young.address()]
+	);
+	assert!(young.is_mature());
+
+	gc.collect(&[Value::Upvalue(upvalue)]);
+	assert_eq!(gc.iter_objects().count(), 2);
+}
+
+#[test]
+fn minor_collections_keep_what_an_aborted_collection_remembers() {
+	let mut gc = GarbageCollector::new();
+	let mut closure = gc.allocate(Closure {
+		position: 0,
+		arity: 0,
+		upvalues: Vec::new(),
+	});
+	gc.collect(&[Value::Closure(closure)]);
+
+	let young = gc.allocate(Value::Nil);
+	closure.upvalues.push(young);
+	gc.remember(closure);
+	gc.set_pause_budget(Some(1));
+	gc.start_collection(&[]);
+
+	// The minor collection aborts the incremental one, which must leave the remembered closure to it.
+	gc.collect_nursery(&[]);
+	assert!(gc
+		.iter_objects()
+		.any(|object| object.address == young.address()));
+	assert_eq!(*closure.upvalues[0], Value::Nil);
+}
+
+#[test]
+fn incremental_collection_in_slices() {
+	let mut gc = GarbageCollector::new();
+	gc.set_pause_budget(Some(1));
+	let mut closure = gc.allocate(Closure {
+		position: 0,
+		arity: 0,
+		upvalues: Vec::new(),
+	});
+	let upvalue = gc.allocate(Value::Nil);
+	gc.allocate(Value::Nil);
+	let kept = gc.allocate(Value::Nil);
+
+	let roots = [Value::Closure(closure), Value::Upvalue(kept)];
+	gc.start_collection(&roots);
+	assert!(gc.is_marking());
+	assert!(!gc.mark_slice());
+	assert!(gc.mark_slice());
+
+	// The closure is marked already, so the upvalue stored into it afterwards is only found through the barrier.
+	closure.upvalues.push(upvalue);
+	gc.remember(closure);
+	let young = gc.allocate(Value::Nil);
+	assert!(gc.mark_slice());
+
+	gc.finish_collection(&roots);
+	assert!(!gc.is_marking());
+	let addresses: Vec<_> = gc.iter_objects().map(|object| object.address).collect();
+	assert_eq!(
+		addresses,
+		[
+			closure.address(),
+			upvalue.address(),
+			kept.address(),
+			young.address()
+		]
+	);
+}
+
+#[test]
+fn heap_statistics() {
+	let mut gc = GarbageCollector::new();
+	let kept = gc.allocate(Value::Nil);
+	gc.allocate(Value::Nil);
+	gc.intern("mussel");
+	let stats = gc.stats();
+	assert_eq!(stats.objects, 3);
+	assert_eq!(stats.interned_strings, 1);
+	assert_eq!(stats.bytes as u64, stats.total_bytes);
+	assert_eq!(stats.collections, 0);
+
+	gc.collect_nursery(&[Value::Upvalue(kept)]);
+	gc.collect(&[Value::Upvalue(kept)]);
+	let after = gc.stats();
+	assert_eq!(after.objects, 1);
+	assert_eq!(after.interned_strings, 0);
+	assert_eq!(after.total_bytes, stats.total_bytes);
+	assert!(after.bytes < stats.bytes);
+	assert_eq!(after.collections, 1);
+	assert_eq!(after.minor_collections, 1);
+	// Timing a collection may round down to zero on a fast enough machine.
+	assert!(after.time >= stats.time);
+}
+
+#[test]
+fn collection_pauses_are_reported() {
+	let mut gc = GarbageCollector::new();
+	let events = Rc::new(RefCell::new(Vec::new()));
+	let log = Rc::clone(&events);
+	gc.on_gc(move |event, stats| {
+		log.borrow_mut()
+			.push((event, stats.collections, stats.minor_collections))
+	});
+	gc.allocate(Value::Nil);
+	gc.collect_nursery(&[]);
+	gc.set_pause_budget(Some(1));
+	gc.start_collection(&[]);
+	while !gc.mark_slice() {}
+	gc.finish_collection(&[]);
+	assert_eq!(
+		*events.borrow(),
+		[
+			(GcEvent::Start, 0, 0),
+			(GcEvent::End, 0, 1),
+			// Every slice of the incremental collection is a pause of its own.
+			(GcEvent::Start, 0, 1),
+			(GcEvent::End, 0, 1),
+			(GcEvent::Start, 0, 1),
+			(GcEvent::End, 0, 1),
+			(GcEvent::Start, 0, 1),
+			(GcEvent::End, 1, 1),
+		]
+	);
+
+	let stats = gc.stats();
+	assert!(stats.last_pause <= stats.max_pause);
+	assert!(stats.max_pause <= stats.time);
+
+	gc.remove_on_gc();
+	gc.collect(&[]);
+	assert_eq!(events.borrow().len(), 8);
+}
+
+#[test]
+fn large_heaps_are_marked() {
+	let mut gc = GarbageCollector::new();
+	// A closure capturing functions and closures, large enough to be marked in parallel with `gc-parallel`.
+	let upvalues = (0..20_000)
+		.map(|i| {
+			let value = if i % 2 == 0 {
+				Value::FunctionPointer(gc.allocate(FunctionPointer {
+					position: 0,
+					arity: 0,
+				}))
+			} else {
+				let inner = gc.allocate(Value::Nil);
+				Value::Closure(gc.allocate(Closure {
+					position: 0,
+					arity: 0,
+					upvalues: vec![inner],
+				}))
+			};
+			gc.allocate(value)
+		})
+		.collect();
+	let closure = gc.allocate(Closure {
+		position: 0,
+		arity: 0,
+		upvalues,
+	});
+	for _ in 0..20_000 {
+		gc.allocate(Value::Nil);
+	}
+
+	gc.collect(&[Value::Closure(closure)]);
+	assert_eq!(gc.stats().objects, 1 + 20_000 + 10_000 + 2 * 10_000);
+	assert!(closure.upvalues.iter().all(|upvalue| upvalue.is_mature()));
+}
+
+#[test]
+fn moving_collections_forward_references() {
+	let mut gc = GarbageCollector::new();
+	gc.set_moving(true);
+	let string = gc.intern("mussel");
+	let upvalue = gc.allocate(Value::String(string));
+	let closure = gc.allocate(Closure {
+		position: 0,
+		arity: 0,
+		upvalues: vec![upvalue],
+	});
+	let pinned = gc.allocate(Value::Nil);
+	let pinned = gc.pin(pinned);
+	gc.allocate(Value::Nil);
+
+	gc.collect(&[Value::Closure(closure)]);
+	let moved = gc.forward(closure);
+	assert_ne!(moved, closure);
+	assert_eq!(gc.forward(pinned.reference()), pinned.reference());
+	assert_eq!(gc.stats().objects, 4);
+
+	// The references inside the heap and the string pool are forwarded by the GC itself.
+	let Value::String(s) = *moved.upvalues[0] else {
+		panic!("the upvalue doesn't box the string");
+	};
+	assert_eq!(s.as_str(), "mussel");
+	assert_eq!(gc.intern("mussel"), s);
+	let mut value = Value::String(string);
+	gc.forward_value(&mut value);
+	assert!(matches!(value, Value::String(forwarded) if forwarded == s));
+}
+
+#[test]
+fn fallible_allocations_respect_the_heap_limit() {
+	let mut gc = GarbageCollector::new();
+	let interned = gc.intern("mussel");
+	gc.set_max_heap_size(gc.bytes_allocated());
+
+	// Interned strings are found without allocating.
+	assert_eq!(gc.try_intern("mussel"), Ok(interned));
+	let error = gc.try_allocate(Value::Nil).unwrap_err();
+	assert_eq!(error.limit, gc.bytes_allocated());
+	assert!(!gc.fits(&String::from("vm")));
+	assert!(gc.try_intern("vm").is_err());
+
+	// Infallible allocations still succeed, exceeding the limit.
+	gc.allocate(Value::Nil);
+	assert!(gc.bytes_allocated() > error.limit);
+	gc.collect(&[Value::String(interned)]);
+	assert!(gc.try_allocate(Value::Nil).is_err());
+	gc.set_max_heap_size(usize::MAX);
+	assert!(gc.try_allocate(Value::Nil).is_ok());
+}
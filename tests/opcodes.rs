@@ -0,0 +1,230 @@
+//! Focused tests of single instructions, each run on a VM state set up beforehand.
+
+use std::ops::Deref;
+
+use mussel_vm::{
+	bytecode::{
+		Bytecode, BytecodeWriter, CallPosition, Constant, ConstantIndex, Emit, Instruction,
+	},
+	gc::{Allocate, Closure, Pinned, Reference},
+	value::Value,
+	vm::{RuntimeErrorKind, VirtualMachine},
+};
+
+/// Sets up a VM state, executes a short instruction sequence on it, and leaves the VM for the test to inspect. Globals
+/// are set up through the VM itself, see [`VirtualMachine::set_global`].
+///
+/// The values allocated by the tester are rooted, so that they're still valid to inspect after collections.
+struct VmTester {
+	/// Declared before the VM, so that the handles are dropped first and nothing is leaked along with the heap.
+	roots: Vec<Pinned<()>>,
+	vm: VirtualMachine,
+	constants: Vec<Constant>,
+}
+
+impl VmTester {
+	fn new() -> Self {
+		Self {
+			roots: Vec::new(),
+			vm: VirtualMachine::new(),
+			constants: Vec::new(),
+		}
+	}
+
+	/// Pushes values onto the stack, which are the locals of the executed instructions.
+	fn push(&mut self, values: impl IntoIterator<Item = Value>) {
+		for value in values {
+			self.vm.stack_mut().push(value);
+		}
+	}
+
+	/// Defines a constant for the executed instructions.
+	fn constant(&mut self, constant: Constant) -> ConstantIndex {
+		self.constants.push(constant);
+		(self.constants.len() - 1) as ConstantIndex
+	}
+
+	fn root(&mut self, value: Value) -> Value {
+		self.roots.extend(self.vm.gc_mut().root(&value));
+		value
+	}
+
+	fn string(&mut self, s: &str) -> Value {
+		let string = self.vm.gc_mut().intern(s);
+		self.root(Value::String(string))
+	}
+
+	/// Allocates a closure, along with the upvalues boxing `upvalues`.
+	fn closure(&mut self, position: CallPosition, upvalues: &[Value]) -> Reference<Closure> {
+		let upvalues = upvalues
+			.iter()
+			.map(|value| self.vm.gc_mut().allocate(value.clone()))
+			.collect();
+		let closure = self.vm.gc_mut().allocate(Closure {
+			position,
+			arity: 0,
+			upvalues,
+		});
+		self.root(Value::Closure(closure));
+		closure
+	}
+
+	/// Executes the instructions, followed by a `Return` which ends the top-level code.
+	fn execute(&mut self, instructions: &[Instruction]) -> Result<(), RuntimeErrorKind> {
+		let mut bytecode = Bytecode {
+			code: Vec::new(),
+			constants: self.constants.clone(),
+		};
+		let mut writer = BytecodeWriter::new(&mut bytecode);
+		for instruction in instructions {
+			writer.emit(*instruction);
+		}
+		writer.emit(Instruction::Return);
+		self.vm.interpret(&bytecode).map_err(|error| error.kind)
+	}
+
+	fn stack(&self) -> &[Value] {
+		self.vm.stack()
+	}
+
+	fn objects(&self) -> usize {
+		self.vm.gc().iter_objects().count()
+	}
+}
+
+#[test]
+fn capture_boxes_a_local() {
+	let mut tester = VmTester::new();
+	let closure = tester.closure(0, &[]);
+	tester.push([Value::Number(1.0), Value::Closure(closure)]);
+	tester.execute(&[Instruction::Capture(0)]).unwrap();
+
+	let Value::Upvalue(upvalue) = tester.stack()[0] else {
+		panic!("the local is not boxed");
+	};
+	assert_eq!(*upvalue.deref(), Value::Number(1.0));
+	assert_eq!(closure.upvalues, [upvalue]);
+	assert_eq!(tester.objects(), 2);
+}
+
+#[test]
+fn capture_shares_a_boxed_local() {
+	let mut tester = VmTester::new();
+	let first = tester.closure(0, &[]);
+	tester.push([Value::Nil, Value::Closure(first)]);
+	tester
+		.execute(&[
+			Instruction::Capture(0),
+			Instruction::Pop,
+			Instruction::Closure(0, 0),
+			Instruction::Capture(0),
+		])
+		.unwrap();
+
+	let Value::Closure(third) = tester.stack()[1] else {
+		panic!("the closure is not on the stack");
+	};
+	assert_eq!(third.upvalues, first.upvalues);
+	// Both closures and a single upvalue.
+	assert_eq!(tester.objects(), 3);
+}
+
+#[test]
+fn capture_without_closure() {
+	let mut tester = VmTester::new();
+	tester.push([Value::Nil, Value::Number(1.0)]);
+	assert_eq!(
+		tester.execute(&[Instruction::Capture(0)]),
+		Err(RuntimeErrorKind::CaptureWithoutClosure)
+	);
+	assert_eq!(tester.objects(), 0);
+}
+
+#[test]
+fn locals_are_accessed_through_upvalues() {
+	let mut tester = VmTester::new();
+	let closure = tester.closure(0, &[Value::Number(1.0)]);
+	let upvalue = closure.upvalues[0];
+	tester.push([Value::Upvalue(upvalue), Value::Number(2.0)]);
+	tester
+		.execute(&[Instruction::SetLocal(0), Instruction::GetLocal(0)])
+		.unwrap();
+
+	assert_eq!(*upvalue.deref(), Value::Number(2.0));
+	assert!(matches!(tester.stack()[0], Value::Upvalue(u) if u == upvalue));
+	assert_eq!(tester.stack()[2], Value::Number(2.0));
+}
+
+#[test]
+fn upvalues_outside_closures() {
+	let mut tester = VmTester::new();
+	tester.push([Value::Nil]);
+	assert_eq!(
+		tester.execute(&[Instruction::GetUpvalue(0)]),
+		Err(RuntimeErrorKind::UpvalueOutsideClosure)
+	);
+	assert_eq!(
+		tester.execute(&[Instruction::SetUpvalue(0)]),
+		Err(RuntimeErrorKind::UpvalueOutsideClosure)
+	);
+}
+
+#[test]
+fn invoked_closures_read_and_write_upvalues() {
+	let mut tester = VmTester::new();
+	// 00: INVOKE, 01: RETURN, 02: the closure, bumping its upvalue.
+	let closure = tester.closure(2, &[Value::Number(41.0)]);
+	let one = tester.constant(Constant::Number(1.0));
+	tester.push([Value::Closure(closure)]);
+	tester
+		.execute(&[
+			Instruction::Invoke,
+			Instruction::Return,
+			Instruction::GetUpvalue(0),
+			Instruction::Constant(one),
+			Instruction::Add,
+			Instruction::SetUpvalue(0),
+		])
+		.unwrap();
+
+	assert_eq!(tester.stack(), [Value::Number(42.0)]);
+	assert_eq!(*closure.upvalues[0].deref(), Value::Number(42.0));
+}
+
+#[test]
+fn add_interns_concatenated_strings() {
+	let mut tester = VmTester::new();
+	let mussel = tester.string("mussel");
+	let vm = tester.string("vm");
+	let expected = tester.string("musselvm");
+	tester.push([mussel, vm]);
+	tester.execute(&[Instruction::Add]).unwrap();
+
+	let (Value::String(sum), Value::String(expected)) = (&tester.stack()[0], expected) else {
+		panic!("the sum is not a string");
+	};
+	assert_eq!(*sum, expected);
+	assert_eq!(tester.objects(), 3);
+}
+
+#[test]
+fn captured_locals_are_stored_unboxed() {
+	let mut tester = VmTester::new();
+	let closure = tester.closure(0, &[Value::Number(1.0)]);
+	let upvalue = closure.upvalues[0];
+	tester.push([Value::Upvalue(upvalue)]);
+	// The captured local is the stack top, stored into its own slot and a global.
+	tester
+		.execute(&[
+			Instruction::SetLocal(0),
+			Instruction::SetGlobal(0),
+			Instruction::GetLocal(0),
+			Instruction::GetGlobal(0),
+			Instruction::Equal,
+		])
+		.unwrap();
+
+	assert_eq!(*upvalue.deref(), Value::Number(1.0));
+	assert_eq!(tester.stack()[1], Value::Boolean(true));
+	assert_eq!(tester.vm.global(0), Value::Number(1.0));
+}
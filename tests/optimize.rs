@@ -0,0 +1,209 @@
+use mussel_vm::{
+	bytecode,
+	bytecode::{
+		eliminate_dead_code, optimize, thread_jumps, Bytecode, BytecodeWriter, CallPosition,
+		Constant, ConstantIndex, Emit, GlobalIndex, Instruction, JumpOffset, LocalOffset,
+		OperationCode,
+	},
+	value::Value,
+	vm::VirtualMachine,
+};
+
+fn instructions(bytecode: &Bytecode) -> Vec<Instruction> {
+	bytecode
+		.instructions()
+		.map(|(_, instruction)| instruction)
+		.collect()
+}
+
+fn run(bytecode: &Bytecode) -> Value {
+	let mut vm = VirtualMachine::new();
+	vm.interpret(bytecode).unwrap();
+	vm.global(0)
+}
+
+#[test]
+fn dead_code_elimination() {
+	let bytecode = bytecode! {
+		const [Constant::Number(42.0)]
+
+		OperationCode::Call; 13 as CallPosition; 0 as LocalOffset;
+		OperationCode::SetGlobal; 0 as GlobalIndex;
+		OperationCode::Return;
+		// 07: dead, referring to a function which is dead as well.
+		OperationCode::Fun; 21 as CallPosition; 0 as LocalOffset;
+		OperationCode::Pop;
+		OperationCode::Nil;
+		// 13:
+		OperationCode::Constant; 0 as ConstantIndex;
+		OperationCode::Jump; 1 as JumpOffset;
+		OperationCode::Nil;
+		OperationCode::Return;
+		// 21: dead function.
+		OperationCode::Nil;
+		OperationCode::Return;
+	};
+
+	let optimized = eliminate_dead_code(&bytecode);
+	assert_eq!(
+		instructions(&optimized),
+		[
+			Instruction::Call(7, 0),
+			Instruction::SetGlobal(0),
+			Instruction::Return,
+			Instruction::Constant(0),
+			Instruction::Jump(0),
+			Instruction::Return,
+		]
+	);
+	assert_eq!(run(&optimized), run(&bytecode));
+	assert_eq!(run(&optimized), Value::Number(42.0));
+}
+
+#[test]
+fn jump_threading() {
+	let bytecode = bytecode! {
+		const [Constant::Number(42.0)]
+
+		OperationCode::True;
+		OperationCode::JumpIfFalse; 7 as JumpOffset;
+		OperationCode::Pop;
+		OperationCode::Jump; 0 as JumpOffset;
+		// 08:
+		OperationCode::Jump; 4 as JumpOffset;
+		// 11:
+		OperationCode::Nil;
+		OperationCode::Jump; 0 as JumpOffset;
+		// 15:
+		OperationCode::Jump; 3 as JumpOffset;
+		OperationCode::Nil;
+		OperationCode::Nil;
+		OperationCode::Nil;
+		// 21:
+		OperationCode::Constant; 0 as ConstantIndex;
+		OperationCode::SetGlobal; 0 as GlobalIndex;
+		OperationCode::Return;
+	};
+
+	let threaded = thread_jumps(&bytecode);
+	assert_eq!(
+		instructions(&threaded),
+		[
+			Instruction::True,
+			Instruction::Pop,
+			Instruction::Jump(13),
+			Instruction::Jump(10),
+			Instruction::Nil,
+			Instruction::Jump(6),
+			Instruction::Jump(3),
+			Instruction::Nil,
+			Instruction::Nil,
+			Instruction::Nil,
+			Instruction::Constant(0),
+			Instruction::SetGlobal(0),
+			Instruction::Return,
+		]
+	);
+
+	let optimized = optimize(&bytecode);
+	assert_eq!(
+		instructions(&optimized),
+		[
+			Instruction::True,
+			Instruction::Pop,
+			Instruction::Constant(0),
+			Instruction::SetGlobal(0),
+			Instruction::Return,
+		]
+	);
+	assert_eq!(run(&optimized), run(&bytecode));
+
+	let bytecode = bytecode! {
+		const []
+
+		OperationCode::False;
+		OperationCode::JumpIfFalse; 2 as JumpOffset;
+		OperationCode::Nil;
+		OperationCode::Pop;
+		OperationCode::Pop;
+		OperationCode::Return;
+	};
+	assert_eq!(
+		instructions(&thread_jumps(&bytecode)),
+		[
+			Instruction::False,
+			Instruction::Jump(2),
+			Instruction::Nil,
+			Instruction::Pop,
+			Instruction::Pop,
+			Instruction::Return,
+		]
+	);
+}
+
+#[test]
+fn jumps_into_instructions_are_left_alone() {
+	let bytecode = bytecode! {
+		const [Constant::Number(42.0)]
+
+		OperationCode::Jump; 0 as JumpOffset;
+		// 03: a jump into the operand of the `Constant` below, with dead code before it.
+		OperationCode::Jump; 3 as JumpOffset;
+		OperationCode::Nil;
+		OperationCode::Pop;
+		OperationCode::Constant; 0 as ConstantIndex;
+		OperationCode::Return;
+	};
+
+	assert_eq!(eliminate_dead_code(&bytecode).code, bytecode.code);
+	assert_eq!(thread_jumps(&bytecode).code, bytecode.code);
+	assert_eq!(optimize(&bytecode).code, bytecode.code);
+}
+
+#[test]
+fn unreachable_garbage_is_removed() {
+	let mut bytecode = bytecode! {
+		const []
+
+		OperationCode::Nil;
+		OperationCode::Return;
+	};
+	bytecode.code.push(0xC8);
+
+	assert_eq!(
+		instructions(&eliminate_dead_code(&bytecode)),
+		[Instruction::Nil, Instruction::Return]
+	);
+	assert_eq!(thread_jumps(&bytecode).code, bytecode.code);
+	assert_eq!(
+		instructions(&optimize(&bytecode)),
+		[Instruction::Nil, Instruction::Return]
+	);
+
+	// Garbage which is reached is left alone.
+	bytecode.code.swap(1, 2);
+	assert_eq!(eliminate_dead_code(&bytecode).code, bytecode.code);
+}
+
+#[test]
+fn threading_out_of_jump_range() {
+	let mut bytecode = Bytecode {
+		code: Vec::new(),
+		constants: Vec::new(),
+	};
+	let mut writer = BytecodeWriter::new(&mut bytecode);
+	// The chain spans more than a single jump can.
+	for _ in 0..2 {
+		writer.emit(Instruction::Jump(30000));
+		for _ in 0..30000 {
+			writer.emit(Instruction::Nil);
+		}
+	}
+	writer.emit(Instruction::Jump(0));
+	writer.emit(Instruction::Return);
+
+	// Only the jump to the next instruction is removed.
+	let threaded = thread_jumps(&bytecode);
+	assert_eq!(threaded.instruction_at(0), Some(Instruction::Jump(30000)));
+	assert_eq!(threaded.code.len(), bytecode.code.len() - 3);
+}
@@ -0,0 +1,90 @@
+use mussel_vm::{
+	bytecode,
+	bytecode::{CallPosition, Constant, ConstantIndex, GlobalIndex, LocalOffset, OperationCode},
+	gc::Allocate,
+	pool::VmPool,
+	value::{OwnedValue, Value},
+	vm::VirtualMachine,
+};
+
+#[test]
+fn pooled_vms_are_recycled() {
+	let bytecode = bytecode! {
+		const []
+
+		OperationCode::True;
+		OperationCode::SetGlobal; 0 as GlobalIndex;
+		OperationCode::Pop;
+		OperationCode::Return;
+	};
+	let pool = VmPool::new(1);
+	assert_eq!(pool.idle(), 1);
+	{
+		let mut vm = pool.acquire();
+		assert_eq!(pool.idle(), 0);
+		vm.interpret(&bytecode).unwrap();
+		assert_eq!(vm.global(0), Value::Boolean(true));
+
+		// The pool grows on demand, but never keeps more than its capacity.
+		let _extra = pool.acquire();
+	}
+	assert_eq!(pool.idle(), 1);
+	assert_eq!(pool.acquire().global(0), Value::Nil);
+}
+
+#[test]
+fn nothing_carries_over_between_jobs() {
+	let bytecode = bytecode! {
+		const [Constant::String("mussel".into()), Constant::String("vm".into())]
+
+		OperationCode::Call; 7 as CallPosition; 0 as LocalOffset;
+		OperationCode::SetGlobal; 0 as GlobalIndex;
+		OperationCode::Return;
+		// 07:
+		OperationCode::Constant; 0 as ConstantIndex;
+		OperationCode::Constant; 1 as ConstantIndex;
+		OperationCode::Add;
+		OperationCode::Return;
+	};
+	let pool = VmPool::with_factory(1, || {
+		let mut vm = VirtualMachine::new();
+		vm.set_max_string_length(8);
+		vm
+	});
+
+	let handle = {
+		let mut vm = pool.acquire();
+		vm.set_max_string_length(1);
+		vm.set_max_call_depth(0);
+		vm.gc_mut().set_max_heap_size(0);
+		vm.interrupt_handle().interrupt();
+		vm.gc_mut()
+			.on_gc(|_, _| panic!("the callback carries over"));
+		vm.load(bytecode.clone())
+	};
+
+	let mut vm = pool.acquire();
+	// The program is unloaded, so the stale handle doesn't refer to the one loaded in its place.
+	assert_ne!(vm.load(bytecode.clone()), handle);
+	vm.interpret(&bytecode).unwrap();
+	assert_eq!(
+		vm.extract(&vm.global(0)),
+		Some(OwnedValue::String("musselvm".into()))
+	);
+	assert!(vm.gc_mut().try_allocate(Value::Nil).is_ok());
+	vm.collect_garbage();
+
+	// The limit configured by the factory is restored rather than dropped.
+	vm.set_max_string_length(usize::MAX);
+	drop(vm);
+	let mut vm = pool.acquire();
+	let bytecode = bytecode! {
+		const [Constant::String("mussel".into()), Constant::String("!!!".into())]
+
+		OperationCode::Constant; 0 as ConstantIndex;
+		OperationCode::Constant; 1 as ConstantIndex;
+		OperationCode::Add;
+		OperationCode::Return;
+	};
+	assert!(vm.interpret(&bytecode).is_err());
+}
@@ -0,0 +1,527 @@
+//! Random programs, run by the VM and by the optimizer.
+//!
+//! Programs are generated with their stack effects and jump targets tracked, so they're always well-formed: calls,
+//! closures, locals, upvalues, branches and bounded loops included. The operand types are tracked as well, but now
+//! and then an operation is generated regardless of them, so some programs fail on purpose. A correct VM must run
+//! every one of them to the end or abort with a [`RuntimeError`](mussel_vm::vm::RuntimeError), without panicking,
+//! and an optimized program must end up the same.
+
+use std::panic::{self, AssertUnwindSafe};
+
+use mussel_vm::{
+	bytecode::{
+		optimize, Bytecode, BytecodeWriter, CallPosition, Constant, ConstantIndex, Emit,
+		GlobalIndex, Instruction, JumpOffset, LocalOffset,
+	},
+	vm::{RuntimeErrorKind, VirtualMachine},
+};
+
+const GLOBALS: usize = 4;
+const FUNCTIONS: usize = 4;
+/// The maximum number of values on the stack of a call frame.
+const MAX_DEPTH: usize = 24;
+/// The maximum nesting of loops, each running at most 3 times.
+const MAX_LOOPS: usize = 2;
+
+/// A xorshift generator, so that every run generates the same programs.
+struct Random(u64);
+
+impl Random {
+	fn next(&mut self) -> u64 {
+		self.0 ^= self.0 << 13;
+		self.0 ^= self.0 >> 7;
+		self.0 ^= self.0 << 17;
+		self.0
+	}
+
+	fn below(&mut self, n: usize) -> usize {
+		(self.next() % n as u64) as usize
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Type {
+	Number,
+	Boolean,
+	Nil,
+	String,
+	/// A function or a closure, by the index of the function.
+	Callable(usize),
+	/// Unknown, e.g. an argument or the result of a call.
+	Any,
+}
+
+/// The signature of a generated function.
+#[derive(Debug, Clone, Copy)]
+struct Signature {
+	arity: usize,
+	/// The number of upvalues the function accesses, which are captured when a closure of it is created.
+	upvalues: usize,
+}
+
+/// The code of the function being generated, or the top-level code.
+#[derive(Default)]
+struct Segment {
+	code: Vec<Instruction>,
+	/// The indices of the instructions which refer to a function by its index rather than its entry, to be fixed up
+	/// once the code is laid out.
+	fixups: Vec<usize>,
+	/// The types of the values on the stack, from the beginning of the call frame.
+	stack: Vec<Type>,
+	/// The stack slots which hold loop counters, never written by anything but the loop itself.
+	protected: Vec<usize>,
+	function: Option<usize>,
+	loops: usize,
+}
+
+struct Generator {
+	random: Random,
+	constants: Vec<Constant>,
+	globals: [Type; GLOBALS],
+	functions: Vec<Signature>,
+	segment: Segment,
+}
+
+impl Generator {
+	fn emit(&mut self, instruction: Instruction) {
+		self.segment.code.push(instruction);
+	}
+
+	/// Emits an instruction referring to a function by its index, which is fixed up once the code is laid out.
+	fn emit_function(&mut self, instruction: Instruction) {
+		self.segment.fixups.push(self.segment.code.len());
+		self.emit(instruction);
+	}
+
+	fn constant(&mut self, constant: Constant) {
+		self.emit(Instruction::Constant(self.constants.len() as ConstantIndex));
+		self.constants.push(constant);
+	}
+
+	fn push(&mut self, typ: Type) {
+		self.segment.stack.push(typ);
+	}
+
+	fn pop(&mut self, n: usize) {
+		let depth = self.segment.stack.len() - n;
+		self.segment.stack.truncate(depth);
+	}
+
+	fn set_top(&mut self, typ: Type) {
+		*self.segment.stack.last_mut().unwrap() = typ;
+	}
+
+	/// Returns a random stack slot which may be written, if any.
+	fn writable_slot(&mut self, below: usize) -> Option<usize> {
+		let slots: Vec<_> = (0..below)
+			.filter(|slot| !self.segment.protected.contains(slot))
+			.collect();
+		(!slots.is_empty()).then(|| slots[self.random.below(slots.len())])
+	}
+
+	/// Generates `length` random steps, never popping the stack below `floor`. Globals are only written at the top
+	/// level, so that their types are always known.
+	fn block(&mut self, floor: usize, length: usize, top_level: bool) {
+		for _ in 0..length {
+			let depth = self.segment.stack.len();
+			let available = depth - floor;
+			let room = depth < MAX_DEPTH;
+			let top = self.segment.stack.last().copied();
+			let second = depth.checked_sub(2).map(|i| self.segment.stack[i]);
+			// Now and then, types are ignored, which makes the program fail if they don't match.
+			let sloppy = self.random.below(48) == 0;
+			match self.random.below(20) {
+				0 if room => {
+					let n = self.random.below(100) as f64 - 50.0;
+					self.constant(Constant::Number(n));
+					self.push(Type::Number);
+				}
+				1 if room => {
+					let s = ["mussel", "vm", ""][self.random.below(3)];
+					self.constant(Constant::String(s.into()));
+					self.push(Type::String);
+				}
+				2 if room => {
+					let (instruction, typ) = [
+						(Instruction::Nil, Type::Nil),
+						(Instruction::True, Type::Boolean),
+						(Instruction::False, Type::Boolean),
+					][self.random.below(3)];
+					self.emit(instruction);
+					self.push(typ);
+				}
+				3 if available >= 1 && (sloppy || top == Some(Type::Number)) => {
+					self.emit(Instruction::Negate);
+					self.set_top(Type::Number);
+				}
+				4 if available >= 1 => {
+					self.emit(Instruction::Not);
+					self.set_top(Type::Boolean);
+				}
+				5 if available >= 2
+					&& (sloppy
+						|| (top == second && matches!(top, Some(Type::Number | Type::String)))) =>
+				{
+					self.emit(Instruction::Add);
+					self.pop(1);
+					self.set_top(top.unwrap());
+				}
+				6 if available >= 2
+					&& (sloppy || (top == Some(Type::Number) && second == Some(Type::Number))) =>
+				{
+					let (instruction, typ) = [
+						(Instruction::Subtract, Type::Number),
+						(Instruction::Multiply, Type::Number),
+						(Instruction::Divide, Type::Number),
+						(Instruction::Greater, Type::Boolean),
+						(Instruction::Less, Type::Boolean),
+					][self.random.below(5)];
+					self.emit(instruction);
+					self.pop(1);
+					self.set_top(typ);
+				}
+				7 if available >= 2 => {
+					self.emit(Instruction::Equal);
+					self.pop(1);
+					self.set_top(Type::Boolean);
+				}
+				8 if available >= 1 => {
+					self.emit(Instruction::Pop);
+					self.pop(1);
+				}
+				9 if available >= 1 && top_level => {
+					let index = self.random.below(GLOBALS);
+					self.emit(Instruction::SetGlobal(index as GlobalIndex));
+					self.globals[index] = top.unwrap();
+				}
+				10 if room => {
+					let index = self.random.below(GLOBALS);
+					self.emit(Instruction::GetGlobal(index as GlobalIndex));
+					self.push(if top_level {
+						self.globals[index]
+					} else {
+						Type::Any
+					});
+				}
+				11 if available >= 1 => self.branch(),
+				12 if room && depth > 0 => {
+					let slot = self.random.below(depth);
+					self.emit(Instruction::GetLocal(slot as LocalOffset));
+					self.push(self.segment.stack[slot]);
+				}
+				13 if depth > 0 => {
+					if let Some(slot) = self.writable_slot(depth) {
+						self.emit(Instruction::SetLocal(slot as LocalOffset));
+						// Written in a branch or a loop, the slot may hold either value afterwards.
+						self.segment.stack[slot] = if slot < floor {
+							Type::Any
+						} else {
+							top.unwrap()
+						};
+					}
+				}
+				14 if depth + 3 < MAX_DEPTH && self.segment.loops < MAX_LOOPS => self.r#loop(),
+				15 => self.call(available, sloppy),
+				16 if room => self.closure(),
+				17 if available >= 1 => self.invoke(available, sloppy),
+				18 => self.upvalue(available, sloppy),
+				_ => {}
+			}
+		}
+	}
+
+	/// Generates `if (top) { ... }`, optionally with an else branch. `JumpIfFalse` keeps the condition, so both
+	/// branches start by popping it.
+	fn branch(&mut self) {
+		let floor = self.segment.stack.len() - 1;
+		let condition = self.segment.code.len();
+		self.emit(Instruction::JumpIfFalse(0));
+		self.emit(Instruction::Pop);
+		self.pop(1);
+		self.arm(floor);
+
+		let skip = self.segment.code.len();
+		self.emit(Instruction::Jump(0));
+		self.patch(condition, self.segment.code.len());
+		self.emit(Instruction::Pop);
+		if self.random.below(2) == 0 {
+			self.arm(floor);
+		}
+		self.patch(skip, self.segment.code.len());
+	}
+
+	/// Generates a loop running up to 3 times, counting down a protected local, i.e.
+	/// `for (var i = n; i > 0; i = i - 1) { ... }`.
+	fn r#loop(&mut self) {
+		let counter = self.segment.stack.len();
+		let iterations = self.random.below(4);
+		self.constant(Constant::Number(iterations as f64));
+		self.push(Type::Number);
+		self.segment.protected.push(counter);
+		self.segment.loops += 1;
+
+		let start = self.segment.code.len();
+		self.emit(Instruction::GetLocal(counter as LocalOffset));
+		self.constant(Constant::Number(0.0));
+		self.emit(Instruction::Greater);
+		let exit = self.segment.code.len();
+		self.emit(Instruction::JumpIfFalse(0));
+		self.emit(Instruction::Pop);
+		self.arm(counter + 1);
+		self.emit(Instruction::GetLocal(counter as LocalOffset));
+		self.constant(Constant::Number(1.0));
+		self.emit(Instruction::Subtract);
+		self.emit(Instruction::SetLocal(counter as LocalOffset));
+		self.emit(Instruction::Pop);
+		let back = self.segment.code.len();
+		self.emit(Instruction::Jump(0));
+		self.patch(back, start);
+		self.patch(exit, self.segment.code.len());
+
+		// The condition, then the counter.
+		self.emit(Instruction::Pop);
+		self.emit(Instruction::Pop);
+		self.pop(1);
+		self.segment.protected.pop();
+		self.segment.loops -= 1;
+	}
+
+	/// Calls a function directly, taking the values on the stack top as the arguments. Functions only call those
+	/// defined after them, so that every program terminates, unless the types are ignored, in which case a function
+	/// may recurse until the call stack overflows. Functions with upvalues are only called this way by accident as
+	/// well.
+	fn call(&mut self, available: usize, sloppy: bool) {
+		let first = self
+			.segment
+			.function
+			.map_or(0, |f| f + usize::from(!sloppy));
+		if first >= FUNCTIONS {
+			return;
+		}
+		let index = first + self.random.below(FUNCTIONS - first);
+		let signature = self.functions[index];
+		if available < signature.arity || (signature.upvalues > 0 && !sloppy) {
+			return;
+		}
+		let arity = signature.arity as LocalOffset;
+		if self.random.below(2) == 0 {
+			self.emit_function(Instruction::Call(index as CallPosition, arity));
+		} else {
+			if self.segment.stack.len() >= MAX_DEPTH {
+				return;
+			}
+			self.emit_function(Instruction::Fun(index as CallPosition, arity));
+			self.emit(Instruction::Invoke);
+		}
+		self.pop(signature.arity);
+		self.push(Type::Any);
+	}
+
+	/// Creates a closure, capturing as many locals as the function accesses upvalues.
+	fn closure(&mut self) {
+		let first = self.segment.function.map_or(0, |f| f + 1);
+		if first >= FUNCTIONS {
+			return;
+		}
+		let index = first + self.random.below(FUNCTIONS - first);
+		let signature = self.functions[index];
+		let depth = self.segment.stack.len();
+		let mut captures = Vec::new();
+		for _ in 0..signature.upvalues {
+			match self.writable_slot(depth) {
+				Some(slot) => captures.push(slot),
+				None => return,
+			}
+		}
+		self.emit_function(Instruction::Closure(
+			index as CallPosition,
+			signature.arity as LocalOffset,
+		));
+		for slot in captures {
+			self.emit(Instruction::Capture(slot as LocalOffset));
+			// Closures may write to it whenever they're invoked.
+			self.segment.stack[slot] = Type::Any;
+		}
+		self.push(Type::Callable(index));
+	}
+
+	/// Invokes the function or closure on the stack top. If the types are ignored, a value known not to be callable is
+	/// invoked as well. Values of unknown types are never invoked, since their arity is unknown.
+	fn invoke(&mut self, available: usize, sloppy: bool) {
+		let arity = match self.segment.stack.last() {
+			Some(Type::Callable(index)) => self.functions[*index].arity,
+			Some(Type::Any) => return,
+			_ if sloppy => 0,
+			_ => return,
+		};
+		if available < arity + 1 {
+			return;
+		}
+		self.emit(Instruction::Invoke);
+		self.pop(arity + 1);
+		self.push(Type::Any);
+	}
+
+	/// Reads or writes an upvalue of the closure being generated. If the types are ignored, any upvalue is accessed,
+	/// even outside closures.
+	fn upvalue(&mut self, available: usize, sloppy: bool) {
+		let upvalues = self
+			.segment
+			.function
+			.map_or(0, |index| self.functions[index].upvalues);
+		let index = match (upvalues, sloppy) {
+			(0, false) => return,
+			(0, true) => 0,
+			(n, _) => self.random.below(n + usize::from(sloppy)),
+		};
+		if self.random.below(2) == 0 && available >= 1 {
+			self.emit(Instruction::SetUpvalue(index as LocalOffset));
+		} else if self.segment.stack.len() < MAX_DEPTH {
+			self.emit(Instruction::GetUpvalue(index as LocalOffset));
+			self.push(Type::Any);
+		}
+	}
+
+	/// Generates a branch arm or a loop body, which leaves the stack as deep as it starts.
+	fn arm(&mut self, floor: usize) {
+		let length = self.random.below(6);
+		self.block(floor, length, false);
+		while self.segment.stack.len() > floor {
+			self.emit(Instruction::Pop);
+			self.pop(1);
+		}
+	}
+
+	/// Points the jump at `from` to the instruction at `to`.
+	fn patch(&mut self, from: usize, to: usize) {
+		let code = &self.segment.code;
+		let size =
+			|range: &[Instruction]| range.iter().map(Instruction::size).sum::<usize>() as i64;
+		let offset = if to > from {
+			size(&code[from + 1..to])
+		} else {
+			-size(&code[to..=from])
+		} as JumpOffset;
+		self.segment.code[from] = match code[from] {
+			Instruction::Jump(_) => Instruction::Jump(offset),
+			_ => Instruction::JumpIfFalse(offset),
+		};
+	}
+
+	/// Generates the body of a function, whose arguments are its first locals, returning the value on the stack top.
+	fn function(&mut self, index: usize) -> Segment {
+		let signature = self.functions[index];
+		let top_level = std::mem::replace(
+			&mut self.segment,
+			Segment {
+				stack: vec![Type::Any; signature.arity],
+				function: Some(index),
+				..Segment::default()
+			},
+		);
+		let length = self.random.below(16);
+		self.block(0, length, false);
+		if self.segment.stack.is_empty() {
+			self.emit(Instruction::Nil);
+		}
+		self.emit(Instruction::Return);
+		std::mem::replace(&mut self.segment, top_level)
+	}
+
+	fn generate(seed: u64) -> Bytecode {
+		let mut random = Random(seed);
+		let functions = (0..FUNCTIONS)
+			.map(|_| Signature {
+				arity: random.below(3),
+				upvalues: random.below(3),
+			})
+			.collect();
+		let mut generator = Generator {
+			random,
+			constants: Vec::new(),
+			globals: [Type::Nil; GLOBALS],
+			functions,
+			segment: Segment::default(),
+		};
+		let functions: Vec<_> = (0..FUNCTIONS)
+			.map(|index| generator.function(index))
+			.collect();
+		generator.block(0, 64, true);
+		generator.emit(Instruction::Return);
+
+		// The top-level code comes first, followed by the functions.
+		let segments: Vec<_> = std::iter::once(generator.segment)
+			.chain(functions)
+			.collect();
+		let mut entries = Vec::new();
+		let mut position = 0;
+		for segment in &segments {
+			entries.push(position);
+			position += segment.code.iter().map(Instruction::size).sum::<usize>();
+		}
+
+		let mut bytecode = Bytecode {
+			code: Vec::new(),
+			constants: generator.constants,
+		};
+		let mut writer = BytecodeWriter::new(&mut bytecode);
+		for mut segment in segments {
+			for index in segment.fixups {
+				// The top-level code is the first segment, so the function entries are off by one.
+				let entry = |function: CallPosition| entries[function as usize + 1] as CallPosition;
+				segment.code[index] = match segment.code[index] {
+					Instruction::Fun(function, arity) => Instruction::Fun(entry(function), arity),
+					Instruction::Closure(function, arity) => {
+						Instruction::Closure(entry(function), arity)
+					}
+					Instruction::Call(function, arity) => Instruction::Call(entry(function), arity),
+					instruction => unreachable!("{} doesn't refer to a function", instruction),
+				};
+			}
+			for instruction in segment.code {
+				writer.emit(instruction);
+			}
+		}
+		bytecode
+	}
+}
+
+/// Runs a program and renders its globals, or returns the error aborting it. Globals are compared as strings, since
+/// dividing by zero yields NaN.
+///
+/// With `moving`, nearly every allocation runs a moving collection, so that a reference the VM fails to forward is
+/// caught as a use after free (with `gc-validate`).
+fn run(bytecode: &Bytecode, moving: bool) -> Result<Vec<String>, RuntimeErrorKind> {
+	let mut vm = VirtualMachine::new();
+	if moving {
+		vm.gc_mut().set_moving(true);
+		vm.gc_mut().set_collection_threshold(0);
+	}
+	vm.interpret(bytecode).map_err(|error| error.kind)?;
+	Ok((0..GLOBALS)
+		.map(|index| format!("{:?}", vm.extract(&vm.global(index as GlobalIndex))))
+		.collect())
+}
+
+#[test]
+fn random_programs_run_and_optimize_correctly() {
+	let mut failures = 0;
+	for seed in 1..=300 {
+		let bytecode = Generator::generate(seed);
+		let result = panic::catch_unwind(AssertUnwindSafe(|| run(&bytecode, false)))
+			.unwrap_or_else(|_| panic!("the VM panicked on seed {}", seed));
+		failures += usize::from(result.is_err());
+
+		let optimized = optimize(&bytecode);
+		assert!(optimized.code.len() <= bytecode.code.len());
+		assert_eq!(run(&optimized, false), result, "seed {}", seed);
+		assert_eq!(
+			run(&bytecode, true),
+			result,
+			"seed {} with a moving GC",
+			seed
+		);
+	}
+	// Both the programs running to the end and those failing are covered.
+	assert!(failures > 0 && failures < 300, "{} failures", failures);
+}
@@ -0,0 +1,90 @@
+#![cfg(feature = "tracing")]
+
+use std::sync::{
+	atomic::{AtomicU64, Ordering},
+	Arc, Mutex,
+};
+
+use mussel_vm::{
+	bytecode,
+	bytecode::{CallPosition, GlobalIndex, LocalOffset, OperationCode},
+	vm::VirtualMachine,
+};
+use tracing::{
+	field::{Field, Visit},
+	span::{Attributes, Id, Record},
+	Event, Metadata, Subscriber,
+};
+
+/// Records every span opened, rendered as its name followed by its fields.
+#[derive(Default)]
+struct Recorder {
+	spans: Arc<Mutex<Vec<String>>>,
+	next: AtomicU64,
+}
+
+struct Rendered(String);
+
+impl Visit for Rendered {
+	fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+		self.0.push_str(&format!(" {}={:?}", field.name(), value));
+	}
+}
+
+impl Subscriber for Recorder {
+	fn enabled(&self, _: &Metadata<'_>) -> bool {
+		true
+	}
+
+	fn new_span(&self, span: &Attributes<'_>) -> Id {
+		let mut rendered = Rendered(span.metadata().name().to_string());
+		span.record(&mut rendered);
+		self.spans.lock().unwrap().push(rendered.0);
+		Id::from_u64(self.next.fetch_add(1, Ordering::Relaxed) + 1)
+	}
+
+	fn record(&self, _: &Id, _: &Record<'_>) {}
+
+	fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+	fn event(&self, _: &Event<'_>) {}
+
+	fn enter(&self, _: &Id) {}
+
+	fn exit(&self, _: &Id) {}
+}
+
+#[test]
+fn calls_and_collections_open_spans() {
+	let bytecode = bytecode! {
+		const []
+
+		OperationCode::Call; 7 as CallPosition; 0 as LocalOffset;
+		OperationCode::SetGlobal; 0 as GlobalIndex;
+		OperationCode::Return;
+		// 07: fun answer() { return helper(); }
+		OperationCode::Call; 12 as CallPosition; 0 as LocalOffset;
+		OperationCode::Return;
+		// 12: fun helper() {}
+		OperationCode::Nil;
+		OperationCode::Return;
+	};
+	let recorder = Recorder::default();
+	let spans = Arc::clone(&recorder.spans);
+	tracing::subscriber::with_default(recorder, || {
+		let mut vm = VirtualMachine::new();
+		let handle = vm.load_with_entries(bytecode, [("answer", 7)]);
+		vm.run(handle).unwrap();
+		vm.collect_garbage();
+	});
+
+	// Functions without entry points are only known by their positions, and
+	// gc-stress adds a collection at every safepoint besides the explicit one.
+	let spans = spans.lock().unwrap();
+	let calls: Vec<_> = spans
+		.iter()
+		.filter(|span| span.starts_with("call"))
+		.collect();
+	assert_eq!(calls, ["call entry=7 function=\"answer\"", "call entry=12"]);
+	assert_eq!(spans.last().unwrap(), "gc objects=0");
+}
@@ -0,0 +1,131 @@
+use mussel_vm::{
+	bytecode,
+	bytecode::{CallPosition, Constant, ConstantIndex, GlobalIndex, LocalOffset, OperationCode},
+	gc::{Allocate, GarbageCollector},
+	value::Value,
+	vm::VirtualMachine,
+};
+
+#[test]
+fn primitives() {
+	let bytecode = bytecode! {
+		const [Constant::Number(42.0)]
+
+		OperationCode::Constant; 0 as ConstantIndex;
+		OperationCode::SetGlobal; 0 as GlobalIndex;
+		OperationCode::Pop;
+		OperationCode::True;
+		OperationCode::SetGlobal; 1 as GlobalIndex;
+		OperationCode::Pop;
+		OperationCode::False;
+		OperationCode::SetGlobal; 2 as GlobalIndex;
+		OperationCode::Pop;
+		OperationCode::Nil;
+		OperationCode::SetGlobal; 3 as GlobalIndex;
+		OperationCode::Pop;
+		OperationCode::Return;
+	};
+	let mut vm = VirtualMachine::new();
+	vm.interpret(&bytecode).unwrap();
+
+	assert_eq!(vm.global(0), Value::Number(42.0));
+	assert_eq!(vm.global(1), Value::Boolean(true));
+	assert_eq!(vm.global(2), Value::Boolean(false));
+	assert_eq!(vm.global(3), Value::Nil);
+	assert_eq!(vm.global(0).to_string(), "42");
+	assert_eq!(vm.global(1).to_string(), "true");
+	assert_eq!(vm.global(3).to_string(), "nil");
+	assert_ne!(vm.global(1), vm.global(2));
+	assert_ne!(vm.global(2), vm.global(3));
+}
+
+#[test]
+fn strings() {
+	let bytecode = bytecode! {
+		const [Constant::String("mus".into()), Constant::String("sel".into())]
+
+		OperationCode::Constant; 0 as ConstantIndex;
+		OperationCode::Constant; 1 as ConstantIndex;
+		OperationCode::Add;
+		OperationCode::SetGlobal; 0 as GlobalIndex;
+		OperationCode::Pop;
+		OperationCode::Return;
+	};
+	let mut vm = VirtualMachine::new();
+	vm.interpret(&bytecode).unwrap();
+
+	let mut gc = GarbageCollector::new();
+	let expected = Value::String(gc.allocate(String::from("mussel")));
+	assert_eq!(vm.global(0), expected);
+	assert_eq!(vm.global(0).to_string(), "mussel");
+}
+
+#[test]
+fn function_pointers() {
+	let bytecode = bytecode! {
+		const []
+
+		OperationCode::Fun; 0x10 as CallPosition; 2 as LocalOffset;
+		OperationCode::SetGlobal; 0 as GlobalIndex;
+		OperationCode::Pop;
+		OperationCode::Fun; 0x10 as CallPosition; 2 as LocalOffset;
+		OperationCode::SetGlobal; 1 as GlobalIndex;
+		OperationCode::Pop;
+		OperationCode::Return;
+	};
+	let mut vm = VirtualMachine::new();
+	vm.interpret(&bytecode).unwrap();
+
+	assert!(matches!(vm.global(0), Value::FunctionPointer(_)));
+	assert_eq!(vm.global(0), vm.global(1));
+	assert_eq!(vm.global(0).to_string(), "<fun position=0x0010 arity=2>");
+}
+
+#[test]
+fn closures_and_upvalues() {
+	let bytecode = bytecode! {
+		const [Constant::Number(1.0)]
+
+		// var x = 1;
+		OperationCode::Constant; 0 as ConstantIndex;
+		// Two closures capturing `x`, which boxes the local into an upvalue.
+		OperationCode::Closure; 0x30 as CallPosition; 0 as LocalOffset;
+		OperationCode::Capture; 0 as LocalOffset;
+		OperationCode::SetGlobal; 0 as GlobalIndex;
+		OperationCode::Pop;
+		OperationCode::Closure; 0x30 as CallPosition; 0 as LocalOffset;
+		OperationCode::Capture; 0 as LocalOffset;
+		OperationCode::SetGlobal; 1 as GlobalIndex;
+		OperationCode::Pop;
+		// Reading the boxed local yields the plain number.
+		OperationCode::GetLocal; 0 as LocalOffset;
+		OperationCode::SetGlobal; 2 as GlobalIndex;
+		OperationCode::Pop;
+		OperationCode::Return;
+	};
+	let mut vm = VirtualMachine::new();
+	vm.interpret(&bytecode).unwrap();
+
+	let first = vm.global(0);
+	let second = vm.global(1);
+	assert!(matches!(first, Value::Closure(_)));
+	assert_eq!(first, first.clone());
+	assert_ne!(first, second);
+	assert_eq!(first.to_string(), "<closure position=0x0030 arity=0>");
+	assert_eq!(vm.global(2), Value::Number(1.0));
+}
+
+#[test]
+fn upvalues_are_transparent() {
+	let mut gc = GarbageCollector::new();
+	let boxed = Value::Upvalue(gc.allocate(Value::Number(114514.0)));
+
+	assert_eq!(boxed, Value::Number(114514.0));
+	assert_eq!(Value::Number(114514.0), boxed);
+	assert_eq!(boxed, boxed.clone());
+	assert_eq!(boxed.unbox(), Value::Number(114514.0));
+	assert!(boxed.as_boolean());
+	// Only displaying tells an upvalue apart, e.g. when debugging the VM.
+	assert_eq!(boxed.to_string(), "<upvalue 114514>");
+	assert_eq!(boxed.unbox().to_string(), "114514");
+}
@@ -0,0 +1,642 @@
+use mussel_vm::{
+	bytecode,
+	bytecode::{
+		Bytecode, BytecodeWriter, CallPosition, Constant, ConstantIndex, Emit, GlobalIndex,
+		JumpOffset, LocalOffset, OperationCode,
+	},
+	gc::{AllocationKind, HeapExhausted},
+	value::{OwnedValue, Value},
+	vm::{RuntimeErrorKind, TraceFrame, VirtualMachine, DEFAULT_MAX_CALL_DEPTH, LOCALS_CAPACITY},
+};
+
+/// Pushes `n` nils onto the stack at the beginning of the program.
+fn fill_stack(writer: &mut BytecodeWriter, n: usize) {
+	for _ in 0..n {
+		writer.emit(OperationCode::Nil);
+	}
+}
+
+#[test]
+fn reset_heap_recycles_vm() {
+	let bytecode = bytecode! {
+		const [Constant::String("job".into())]
+
+		OperationCode::Constant; 0 as ConstantIndex;
+		OperationCode::Constant; 0 as ConstantIndex;
+		OperationCode::Add;
+		OperationCode::SetGlobal; 0 as GlobalIndex;
+		OperationCode::Pop;
+		OperationCode::Return;
+	};
+	let mut vm = VirtualMachine::default();
+	for _ in 0..3 {
+		vm.interpret(&bytecode).unwrap();
+		assert_eq!(vm.global(0).to_string(), "jobjob");
+		vm.reset_heap();
+		assert_eq!(vm.global(0), Value::Nil);
+	}
+}
+
+#[test]
+fn call_frame_at_the_end_of_a_full_stack() {
+	let mut bytecode = Bytecode {
+		code: Vec::new(),
+		constants: Vec::new(),
+	};
+	let mut writer = BytecodeWriter::new(&mut bytecode);
+	writer.define(Constant::Number(7.0));
+	fill_stack(&mut writer, LOCALS_CAPACITY - 1);
+	writer.emit(OperationCode::Constant);
+	writer.emit(0 as ConstantIndex);
+	// The stack is full now, and the call frame starts at its last slot.
+	let entry = LOCALS_CAPACITY - 1 + 3 + 4 + 3;
+	writer.emit(OperationCode::Call);
+	writer.emit(entry as CallPosition);
+	writer.emit(1 as LocalOffset);
+	writer.emit(OperationCode::SetGlobal);
+	writer.emit(0 as GlobalIndex);
+	writer.emit(OperationCode::Return);
+	// identity(x) { return x; }
+	writer.emit(OperationCode::Return);
+
+	let mut vm = VirtualMachine::new();
+	vm.interpret(&bytecode).unwrap();
+	assert_eq!(vm.global(0), Value::Number(7.0));
+}
+
+#[test]
+fn locals_beyond_u8_frame_arithmetic() {
+	let mut bytecode = Bytecode {
+		code: Vec::new(),
+		constants: Vec::new(),
+	};
+	let mut writer = BytecodeWriter::new(&mut bytecode);
+	fill_stack(&mut writer, 200);
+	let entry = 200 + 4 + 3;
+	writer.emit(OperationCode::Call);
+	writer.emit(entry as CallPosition);
+	writer.emit(0 as LocalOffset);
+	writer.emit(OperationCode::SetGlobal);
+	writer.emit(0 as GlobalIndex);
+	writer.emit(OperationCode::Return);
+	// The callee's frame starts at slot 200, and fills the stack up to its last slot.
+	fill_stack(&mut writer, 55);
+	writer.emit(OperationCode::True);
+	writer.emit(OperationCode::SetLocal);
+	writer.emit(54 as LocalOffset);
+	writer.emit(OperationCode::Pop);
+	writer.emit(OperationCode::GetLocal);
+	writer.emit(54 as LocalOffset);
+	writer.emit(OperationCode::Return);
+
+	let mut vm = VirtualMachine::new();
+	vm.interpret(&bytecode).unwrap();
+	assert_eq!(vm.global(0), Value::Boolean(true));
+}
+
+#[test]
+fn local_slot_out_of_stack_does_not_wrap() {
+	let mut bytecode = Bytecode {
+		code: Vec::new(),
+		constants: Vec::new(),
+	};
+	let mut writer = BytecodeWriter::new(&mut bytecode);
+	fill_stack(&mut writer, 200);
+	writer.emit(OperationCode::Call);
+	writer.emit((200 + 4) as CallPosition);
+	writer.emit(0 as LocalOffset);
+	// Slot 200 + 100 would wrap to slot 44 in u8 arithmetic.
+	writer.emit(OperationCode::GetLocal);
+	writer.emit(100 as LocalOffset);
+	writer.emit(OperationCode::Return);
+
+	let mut vm = VirtualMachine::new();
+	let error = vm.interpret(&bytecode).unwrap_err();
+	assert_eq!(error.kind, RuntimeErrorKind::InvalidLocal(100));
+	assert_eq!(error.position, 200 + 4);
+}
+
+#[test]
+fn infinite_recursion_overflows_the_call_stack() {
+	let bytecode = bytecode! {
+		const []
+
+		// fun forever() { forever(); }
+		OperationCode::Call; 0 as CallPosition; 0 as LocalOffset;
+	};
+	let mut vm = VirtualMachine::new();
+	let error = vm.interpret(&bytecode).unwrap_err();
+	assert_eq!(
+		error.kind,
+		RuntimeErrorKind::CallStackOverflow {
+			limit: DEFAULT_MAX_CALL_DEPTH
+		}
+	);
+	assert_eq!(error.trace.len(), DEFAULT_MAX_CALL_DEPTH + 1);
+
+	vm.reset();
+	vm.set_max_call_depth(8);
+	let error = vm.interpret(&bytecode).unwrap_err();
+	assert_eq!(error.kind, RuntimeErrorKind::CallStackOverflow { limit: 8 });
+	assert_eq!(error.trace.len(), 8 + 1);
+}
+
+#[test]
+fn interrupt_stops_an_infinite_loop() {
+	let bytecode = bytecode! {
+		const []
+
+		// while (true) {}
+		OperationCode::Jump; -3 as JumpOffset;
+	};
+	let mut vm = VirtualMachine::new();
+	let interrupt = vm.interrupt_handle();
+	std::thread::spawn(move || {
+		std::thread::sleep(std::time::Duration::from_millis(10));
+		interrupt.interrupt();
+	});
+	let error = vm.interpret(&bytecode).unwrap_err();
+	assert_eq!(error.kind, RuntimeErrorKind::Interrupted);
+
+	// The request is consumed, so the VM can run again.
+	vm.reset();
+	let bytecode = bytecode! {
+		const []
+
+		OperationCode::Return;
+	};
+	assert_eq!(vm.interpret(&bytecode), Ok(()));
+
+	// A request which isn't noticed before the VM is reset is dropped.
+	let bytecode = bytecode! {
+		const []
+
+		OperationCode::Call; 4 as CallPosition; 0 as LocalOffset;
+		// 04:
+		OperationCode::Nil;
+		OperationCode::Return;
+	};
+	vm.interrupt_handle().interrupt();
+	vm.reset();
+	assert_eq!(vm.interpret(&bytecode), Ok(()));
+}
+
+#[test]
+fn requested_collection_runs_at_a_safepoint() {
+	let bytecode = bytecode! {
+		const []
+
+		// s = nil; var again = true; while (again) { again = false; }
+		OperationCode::Nil;
+		OperationCode::SetGlobal; 0 as GlobalIndex;
+		OperationCode::Pop;
+		OperationCode::True;
+		OperationCode::JumpIfFalse; 5 as JumpOffset;
+		OperationCode::Pop;
+		OperationCode::False;
+		OperationCode::Jump; -8 as JumpOffset;
+		OperationCode::Pop;
+		OperationCode::Return;
+	};
+	let mut vm = VirtualMachine::new();
+	let string = vm.inject(OwnedValue::String("dead".into()));
+	vm.set_global(0, string);
+	vm.interrupt_handle().request_collection();
+	vm.interpret(&bytecode).unwrap();
+
+	// Nothing is allocated, but the loop lets the collector free the dead string.
+	assert_eq!(vm.gc().stats().collections, 1);
+	assert_eq!(vm.gc().stats().objects, 0);
+}
+
+#[test]
+fn string_length_limit() {
+	let bytecode = bytecode! {
+		const [Constant::String("mussel!!".into())]
+
+		// var s = "mussel!!"; while (true) { s = s + s; }
+		OperationCode::Constant; 0 as ConstantIndex;
+		OperationCode::SetGlobal; 0 as GlobalIndex;
+		OperationCode::Pop;
+		OperationCode::GetGlobal; 0 as GlobalIndex;
+		OperationCode::GetGlobal; 0 as GlobalIndex;
+		OperationCode::Add;
+		OperationCode::SetGlobal; 0 as GlobalIndex;
+		OperationCode::Pop;
+		OperationCode::Jump; -11 as JumpOffset;
+	};
+	let mut vm = VirtualMachine::new();
+	vm.set_max_string_length(100);
+	let error = vm.interpret(&bytecode).unwrap_err();
+	assert_eq!(
+		error.kind,
+		RuntimeErrorKind::StringTooLong {
+			length: 128,
+			limit: 100
+		}
+	);
+	assert_eq!(
+		error.to_string(),
+		"string of 128 bytes exceeds the length limit of 100 bytes at 0x000A\n\tat 0x000A in script"
+	);
+}
+
+#[test]
+fn heap_limit() {
+	let garbage = bytecode! {
+		const [Constant::Number(1000.0), Constant::Number(0.0), Constant::Number(1.0)]
+
+		// var i = 1000; while (i > 0) { fun() {}; i = i - 1; }
+		OperationCode::Constant; 0 as ConstantIndex;
+		OperationCode::SetGlobal; 0 as GlobalIndex;
+		OperationCode::Pop;
+		// 06:
+		OperationCode::GetGlobal; 0 as GlobalIndex;
+		OperationCode::Constant; 1 as ConstantIndex;
+		OperationCode::Greater;
+		OperationCode::JumpIfFalse; 18 as JumpOffset;
+		OperationCode::Pop;
+		OperationCode::Fun; 0 as CallPosition; 0 as LocalOffset;
+		OperationCode::Pop;
+		OperationCode::GetGlobal; 0 as GlobalIndex;
+		OperationCode::Constant; 2 as ConstantIndex;
+		OperationCode::Subtract;
+		OperationCode::SetGlobal; 0 as GlobalIndex;
+		OperationCode::Pop;
+		OperationCode::Jump; -27 as JumpOffset;
+		// 21:
+		OperationCode::Pop;
+		OperationCode::Return;
+	};
+	let mut vm = VirtualMachine::new();
+	vm.gc_mut().set_max_heap_size(4096);
+	// Far more garbage than the limit is made, which forced collections get rid of.
+	vm.interpret(&garbage).unwrap();
+	assert!(vm.gc().bytes_allocated() <= 4096);
+	assert!(vm.gc().stats().collections > 0);
+
+	let doubling = bytecode! {
+		const [Constant::String("mussel!!".into())]
+
+		// var s = "mussel!!"; while (true) { s = s + s; }
+		OperationCode::Constant; 0 as ConstantIndex;
+		OperationCode::SetGlobal; 0 as GlobalIndex;
+		OperationCode::Pop;
+		OperationCode::GetGlobal; 0 as GlobalIndex;
+		OperationCode::GetGlobal; 0 as GlobalIndex;
+		OperationCode::Add;
+		OperationCode::SetGlobal; 0 as GlobalIndex;
+		OperationCode::Pop;
+		OperationCode::Jump; -11 as JumpOffset;
+	};
+	vm.reset();
+	let error = vm.interpret(&doubling).unwrap_err();
+	assert!(matches!(
+		error.kind,
+		RuntimeErrorKind::OutOfMemory(HeapExhausted { limit: 4096, .. })
+	));
+	assert_eq!(error.position, 0x000A);
+	assert!(error.to_string().starts_with("out of memory, allocating "));
+	assert!(vm.gc().bytes_allocated() <= 4096);
+}
+
+#[test]
+fn runtime_errors_carry_a_stack_trace() {
+	let bytecode = bytecode! {
+		const []
+
+		OperationCode::Fun; 7 as CallPosition; 0 as LocalOffset;
+		OperationCode::Invoke;
+		OperationCode::Pop;
+		OperationCode::Return;
+		// 07: fun outer() { inner(); }
+		OperationCode::Call; 12 as CallPosition; 0 as LocalOffset;
+		OperationCode::Return;
+		// 12: fun inner() { -true; }
+		OperationCode::True;
+		OperationCode::Negate;
+		OperationCode::Return;
+	};
+	let mut vm = VirtualMachine::new();
+	let error = vm.interpret(&bytecode).unwrap_err();
+	assert_eq!(error.position, 13);
+	assert_eq!(
+		error.trace,
+		[
+			TraceFrame {
+				function: Some(12),
+				position: 13
+			},
+			TraceFrame {
+				function: Some(7),
+				position: 7
+			},
+			TraceFrame {
+				function: None,
+				position: 4
+			},
+		]
+	);
+	assert_eq!(
+		error.to_string(),
+		"operator `-` can only be applied to numbers at 0x000D\n\tat 0x000D in function 0x000C\n\tat 0x0007 in \
+		 function 0x0007\n\tat 0x0004 in script"
+	);
+}
+
+#[test]
+fn extracted_values_outlive_the_vm() {
+	let bytecode = bytecode! {
+		const [Constant::String("mussel".into()), Constant::Number(42.0)]
+
+		OperationCode::Constant; 0 as ConstantIndex;
+		OperationCode::SetGlobal; 0 as GlobalIndex;
+		OperationCode::Constant; 1 as ConstantIndex;
+		OperationCode::SetGlobal; 1 as GlobalIndex;
+		OperationCode::Fun; 0 as CallPosition; 0 as LocalOffset;
+		OperationCode::SetGlobal; 2 as GlobalIndex;
+		OperationCode::Return;
+	};
+	let mut vm = VirtualMachine::new();
+	vm.interpret(&bytecode).unwrap();
+	let string = vm.extract(&vm.global(0));
+	let number = vm.extract(&vm.global(1));
+	assert_eq!(vm.extract(&vm.global(2)), None);
+	assert_eq!(vm.extract(&vm.global(3)), Some(OwnedValue::Nil));
+	drop(vm);
+
+	assert_eq!(string, Some(OwnedValue::String("mussel".into())));
+	assert_eq!(number, Some(OwnedValue::Number(42.0)));
+}
+
+#[test]
+fn injected_values_are_visible_to_scripts() {
+	let bytecode = bytecode! {
+		const [Constant::String(", mussel".into())]
+
+		// greeting = greeting + ", mussel";
+		OperationCode::GetGlobal; 0 as GlobalIndex;
+		OperationCode::Constant; 0 as ConstantIndex;
+		OperationCode::Add;
+		OperationCode::SetGlobal; 0 as GlobalIndex;
+		OperationCode::Return;
+	};
+	let mut vm = VirtualMachine::new();
+	let greeting = vm.inject("hello".into());
+	vm.set_global(0, greeting);
+	vm.interpret(&bytecode).unwrap();
+	assert_eq!(
+		vm.extract(&vm.global(0)),
+		Some(OwnedValue::String("hello, mussel".into()))
+	);
+	assert_eq!(vm.inject(OwnedValue::from(1.0)), Value::Number(1.0));
+}
+
+#[test]
+fn resident_programs_share_globals() {
+	let initialize = bytecode! {
+		const [Constant::Number(0.0)]
+
+		OperationCode::Constant; 0 as ConstantIndex;
+		OperationCode::SetGlobal; 0 as GlobalIndex;
+		OperationCode::Pop;
+		OperationCode::Return;
+	};
+	let increment = bytecode! {
+		const [Constant::Number(1.0)]
+
+		OperationCode::GetGlobal; 0 as GlobalIndex;
+		OperationCode::Constant; 0 as ConstantIndex;
+		OperationCode::Add;
+		OperationCode::SetGlobal; 0 as GlobalIndex;
+		OperationCode::Pop;
+		OperationCode::Return;
+	};
+	let mut vm = VirtualMachine::new();
+	let initialize = vm.load(initialize);
+	let increment = vm.load(increment);
+	assert_ne!(initialize, increment);
+
+	vm.run(initialize).unwrap();
+	for _ in 0..3 {
+		vm.run(increment).unwrap();
+	}
+	assert_eq!(vm.global(0), Value::Number(3.0));
+
+	// Programs stay resident across resets.
+	vm.reset();
+	vm.run(initialize).unwrap();
+	vm.run(increment).unwrap();
+	assert_eq!(vm.global(0), Value::Number(1.0));
+}
+
+#[test]
+#[should_panic(expected = "program 0 is loaded into another VM")]
+fn programs_are_run_by_their_own_vm() {
+	let bytecode = bytecode! {
+		const []
+
+		OperationCode::Return;
+	};
+	let mut vm = VirtualMachine::new();
+	let mut other = VirtualMachine::new();
+	vm.load(bytecode.clone());
+	let handle = other.load(bytecode);
+	let _ = vm.run(handle);
+}
+
+#[test]
+fn entry_points_are_called_by_name() {
+	let bytecode = bytecode! {
+		const []
+
+		OperationCode::Return;
+		// 01: fun add(a, b) { return a + b; }
+		OperationCode::GetLocal; 0 as LocalOffset;
+		OperationCode::GetLocal; 1 as LocalOffset;
+		OperationCode::Add;
+		OperationCode::Return;
+	};
+	let mut vm = VirtualMachine::new();
+	let handle = vm.load_with_entries(bytecode, [("add", 1)]);
+	vm.run(handle).unwrap();
+
+	let sum = vm.call(handle, "add", &[Value::Number(1.0), Value::Number(2.0)]);
+	assert_eq!(sum, Ok(Value::Number(3.0)));
+	assert!(vm.stack().is_empty());
+
+	let error = vm.call(handle, "subtract", &[]).unwrap_err();
+	assert_eq!(
+		error.kind,
+		RuntimeErrorKind::UnknownEntry("subtract".into())
+	);
+
+	let error = vm
+		.call(handle, "add", &[Value::Number(1.0), Value::Nil])
+		.unwrap_err();
+	assert!(matches!(error.kind, RuntimeErrorKind::TypeMismatch { .. }));
+	assert_eq!(
+		error.trace,
+		[TraceFrame {
+			function: Some(1),
+			position: 5
+		}]
+	);
+}
+
+#[test]
+fn collect_garbage_keeps_reachable_values() {
+	let bytecode = bytecode! {
+		const []
+
+		// var kept = fun; var dropped = fun; dropped = nil;
+		OperationCode::Closure; 0 as CallPosition; 0 as LocalOffset;
+		OperationCode::SetGlobal; 0 as GlobalIndex;
+		OperationCode::Pop;
+		OperationCode::Closure; 0 as CallPosition; 0 as LocalOffset;
+		OperationCode::SetGlobal; 1 as GlobalIndex;
+		OperationCode::Pop;
+		OperationCode::Nil;
+		OperationCode::SetGlobal; 1 as GlobalIndex;
+		OperationCode::Pop;
+		OperationCode::Return;
+	};
+	let mut vm = VirtualMachine::new();
+	vm.interpret(&bytecode).unwrap();
+	assert_eq!(vm.gc().iter_objects().count(), 2);
+
+	vm.collect_garbage();
+	let objects: Vec<_> = vm.gc().iter_objects().collect();
+	assert_eq!(objects.len(), 1);
+	let Value::Closure(kept) = vm.global(0) else {
+		panic!("global 0 is not a closure");
+	};
+	assert_eq!(objects[0].address, kept.address());
+}
+
+#[test]
+fn host_roots_survive_collections() {
+	let bytecode = bytecode! {
+		const [Constant::String("mussel".into())]
+
+		OperationCode::Constant; 0 as ConstantIndex;
+		OperationCode::SetGlobal; 0 as GlobalIndex;
+		OperationCode::Pop;
+		OperationCode::Return;
+	};
+	let mut vm = VirtualMachine::new();
+	vm.interpret(&bytecode).unwrap();
+	let value = vm.global(0);
+	let root = vm.gc_mut().root(&value);
+	assert!(vm.gc_mut().root(&Value::Nil).is_none());
+
+	// The VM forgets the string, but the host still holds it.
+	vm.reset();
+	vm.collect_garbage();
+	assert_eq!(
+		vm.extract(&value),
+		Some(OwnedValue::String("mussel".into()))
+	);
+
+	drop(root);
+	vm.collect_garbage();
+	assert_eq!(vm.gc().iter_objects().count(), 0);
+}
+
+#[test]
+fn collections_are_triggered_by_heap_growth() {
+	let bytecode = bytecode! {
+		const [Constant::Number(0.0), Constant::Number(1000.0), Constant::Number(1.0)]
+
+		// for (var i = 0; i < 1000; i = i + 1) { fun f() {} }
+		OperationCode::Constant; 0 as ConstantIndex;
+		OperationCode::SetGlobal; 0 as GlobalIndex;
+		OperationCode::Pop;
+		// 06:
+		OperationCode::GetGlobal; 0 as GlobalIndex;
+		OperationCode::Constant; 1 as ConstantIndex;
+		OperationCode::Less;
+		OperationCode::JumpIfFalse; 18 as JumpOffset;
+		OperationCode::Pop;
+		OperationCode::Closure; 0 as CallPosition; 0 as LocalOffset;
+		OperationCode::Pop;
+		OperationCode::GetGlobal; 0 as GlobalIndex;
+		OperationCode::Constant; 2 as ConstantIndex;
+		OperationCode::Add;
+		OperationCode::SetGlobal; 0 as GlobalIndex;
+		OperationCode::Pop;
+		OperationCode::Jump; -27 as JumpOffset;
+		// 33:
+		OperationCode::Pop;
+		OperationCode::Return;
+	};
+	let mut vm = VirtualMachine::new();
+	vm.gc_mut().set_collection_threshold(1024);
+	vm.interpret(&bytecode).unwrap();
+	assert_eq!(vm.global(0), Value::Number(1000.0));
+	let bytes = vm.gc().bytes_allocated();
+	assert!(bytes <= 2048, "{} bytes allocated", bytes);
+	assert!(vm.gc().iter_objects().count() < 1000);
+}
+
+/// Stores a young function into the upvalue of a closure, and calls it after allocating something else.
+fn store_into_upvalue() -> Bytecode {
+	bytecode! {
+		const []
+
+		OperationCode::Call; 12 as CallPosition; 0 as LocalOffset;
+		OperationCode::SetGlobal; 0 as GlobalIndex;
+		OperationCode::Pop;
+		OperationCode::GetGlobal; 0 as GlobalIndex;
+		OperationCode::Invoke;
+		OperationCode::Pop;
+		OperationCode::Return;
+		// 12: fun make() { var f; fun store() { ... } return store; }
+		OperationCode::Nil;
+		OperationCode::Closure; 20 as CallPosition; 0 as LocalOffset;
+		OperationCode::Capture; 0 as LocalOffset;
+		OperationCode::Return;
+		// 20: fun store() { f = nop; nop; f(); }
+		OperationCode::Fun; 36 as CallPosition; 0 as LocalOffset;
+		OperationCode::SetUpvalue; 0 as LocalOffset;
+		OperationCode::Pop;
+		OperationCode::Fun; 36 as CallPosition; 0 as LocalOffset;
+		OperationCode::Pop;
+		OperationCode::GetUpvalue; 0 as LocalOffset;
+		OperationCode::Invoke;
+		OperationCode::Return;
+		// 36: fun nop() {}
+		OperationCode::Nil;
+		OperationCode::Return;
+	}
+}
+
+#[test]
+fn nursery_keeps_young_values_stored_in_mature_upvalues() {
+	let bytecode = store_into_upvalue();
+	let mut vm = VirtualMachine::new();
+	// Every allocation runs a minor collection, so the closure and its upvalue are mature by the time a young
+	// function is stored into the upvalue.
+	vm.gc_mut().set_nursery_size(0);
+	vm.interpret(&bytecode).unwrap();
+	// The closure, its upvalue, and the function stored into it. The other function is collected when `f` is called.
+	assert_eq!(vm.gc().iter_objects().count(), 3);
+	assert!(vm
+		.gc()
+		.iter_objects()
+		.all(|object| object.kind != AllocationKind::String));
+}
+
+#[test]
+fn incremental_collection_between_instructions() {
+	let bytecode = store_into_upvalue();
+	let mut vm = VirtualMachine::new();
+	// A collection is always due, and every allocation marks a single object, so the heap is mutated in the middle
+	// of collections.
+	vm.gc_mut().set_collection_threshold(0);
+	vm.gc_mut().set_growth_factor(1.0);
+	vm.gc_mut().set_pause_budget(Some(1));
+	vm.interpret(&bytecode).unwrap();
+	vm.collect_garbage();
+	assert_eq!(vm.gc().iter_objects().count(), 3);
+}